@@ -0,0 +1,845 @@
+// Google REST API, Sheets v4 spreadsheet metadata and Drive v3 file list
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value as JsonValue;
+
+use crate::bindings::supabase::wrappers::{
+    http,
+    types::{FdwError, FdwResult, Options, TypeOid},
+    utils,
+};
+use crate::gviz::{a1_start, column_letters, encode_query, gviz_literal};
+use crate::options::parse_list_option;
+use crate::retry;
+
+// spreadsheet metadata from Sheets v4 API, only the requested fields are set
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Spreadsheet {
+    #[serde(default)]
+    pub properties: SpreadsheetProperties,
+    #[serde(default)]
+    pub sheets: Vec<SheetMeta>,
+    #[serde(default)]
+    pub developer_metadata: Vec<DeveloperMetadata>,
+    #[serde(default)]
+    pub named_ranges: Vec<NamedRange>,
+}
+
+impl Spreadsheet {
+    // get the gid and A1 range of a named range, the range is None if the
+    // named range is the whole tab
+    pub fn resolve_named_range(
+        &self,
+        name: &str,
+    ) -> Result<(Option<String>, Option<String>), FdwError> {
+        let named_range = self
+            .named_ranges
+            .iter()
+            .find(|r| r.name == name)
+            .ok_or(format!("named range '{}' is not found", name))?;
+        let range = &named_range.range;
+        let grid = self
+            .sheets
+            .iter()
+            .find(|s| s.properties.sheet_id == range.sheet_id)
+            .map(|s| &s.properties.grid_properties)
+            .ok_or(format!("tab of named range '{}' is not found", name))?;
+        Ok((Some(range.sheet_id.to_string()), range.to_a1(grid)))
+    }
+
+    // get the gid of a filter view given by id or title, and its criteria as
+    // a gviz condition, None if it filters nothing
+    pub fn resolve_filter_view(&self, name: &str) -> Result<(String, Option<String>), FdwError> {
+        let views = || self.sheets.iter().flat_map(|s| &s.filter_views);
+        let view = views()
+            .find(|v| v.filter_view_id.to_string() == name)
+            .or_else(|| views().find(|v| v.title == name))
+            .ok_or(format!("filter view '{}' is not found", name))?;
+        Ok((view.range.sheet_id.to_string(), view.condition()?))
+    }
+
+    // get the gid of the tab of a title
+    pub fn sheet_id_of(&self, title: &str) -> Result<String, FdwError> {
+        self.sheets
+            .iter()
+            .find(|s| s.properties.title == title)
+            .map(|s| s.properties.sheet_id.to_string())
+            .ok_or(format!("tab '{}' is not found", title))
+    }
+
+    // get the title and merges of the tab given by gid, or of the first tab
+    // like gviz reads, with the position of the range read in it
+    pub fn sheet_grid(
+        &self,
+        sheet_id: Option<&str>,
+        range: Option<&str>,
+    ) -> Result<SheetGrid, FdwError> {
+        let sheet = match sheet_id {
+            Some(gid) => self
+                .sheets
+                .iter()
+                .find(|s| s.properties.sheet_id.to_string() == gid)
+                .ok_or(format!("tab gid {} is not found", gid))?,
+            None => self.sheets.first().ok_or("spreadsheet has no tabs")?,
+        };
+        let (row, col) = range.map_or((0, 0), a1_start);
+        Ok(SheetGrid {
+            sheet_id: sheet.properties.sheet_id,
+            title: sheet.properties.title.clone(),
+            row,
+            col,
+            merges: sheet.merges.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+// the tab and range of a scan in the sheet grid, for the cell properties gviz
+// doesn't return, such as merges
+#[derive(Debug, Default, Clone)]
+pub struct SheetGrid {
+    // Sheets v4 API URL of the spreadsheet
+    pub url: String,
+    // gid and title of the tab
+    pub sheet_id: i64,
+    pub title: String,
+    // 0-based row and column of the top-left cell of the range
+    pub row: usize,
+    pub col: usize,
+    pub merges: Vec<GridRange>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamedRange {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub range: GridRange,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SpreadsheetProperties {
+    #[serde(default)]
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SheetMeta {
+    #[serde(default)]
+    pub properties: SheetProperties,
+    #[serde(default)]
+    pub developer_metadata: Vec<DeveloperMetadata>,
+    #[serde(default)]
+    pub data: Vec<GridData>,
+    #[serde(default)]
+    pub protected_ranges: Vec<ProtectedRange>,
+    #[serde(default)]
+    pub filter_views: Vec<FilterView>,
+    #[serde(default)]
+    pub merges: Vec<GridRange>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SheetProperties {
+    #[serde(default)]
+    pub sheet_id: i64,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub index: i64,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub grid_properties: GridProperties,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GridProperties {
+    #[serde(default)]
+    pub row_count: i64,
+    #[serde(default)]
+    pub column_count: i64,
+}
+
+// grid data of a range, either its row and column metadata or the cells of
+// the cell_data columns are requested
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GridData {
+    #[serde(default)]
+    pub row_metadata: Vec<DimensionProperties>,
+    #[serde(default)]
+    pub column_metadata: Vec<DimensionProperties>,
+    // 0-based row and column of the first cell of the row data
+    #[serde(default)]
+    pub start_row: usize,
+    #[serde(default)]
+    pub start_column: usize,
+    #[serde(default)]
+    pub row_data: Vec<RowData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RowData {
+    #[serde(default)]
+    pub values: Vec<CellData>,
+}
+
+// properties of a cell which gviz doesn't return, only the fields of the
+// cell_data columns are requested
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CellData {
+    pub note: Option<String>,
+    // link of the whole cell, e.g. of a HYPERLINK formula, not set if the
+    // cell has more than one link
+    pub hyperlink: Option<String>,
+    // runs of rich text, some of them linked
+    #[serde(default)]
+    pub text_format_runs: Vec<TextFormatRun>,
+    // value as typed into the cell, only its formula is requested
+    pub user_entered_value: Option<ExtendedValue>,
+    // format of the cell as displayed, conditional formatting included, only
+    // its background color and text format are requested
+    pub effective_format: Option<JsonValue>,
+    // smart chips of the cell, e.g. people and file chips
+    #[serde(default)]
+    pub chip_runs: Vec<ChipRun>,
+}
+
+impl CellData {
+    // background color of the cell as "#rrggbb", the color components
+    // left out of the response are 0
+    pub fn background(&self) -> Option<String> {
+        let color = self.effective_format.as_ref()?.get("backgroundColor")?;
+        let component = |name: &str| {
+            let v = color.get(name).and_then(JsonValue::as_f64).unwrap_or(0.0);
+            (v.clamp(0.0, 1.0) * 255.0).round() as u8
+        };
+        Some(format!(
+            "#{:02x}{:02x}{:02x}",
+            component("red"),
+            component("green"),
+            component("blue")
+        ))
+    }
+
+    // link of the first smart chip of the cell, the email of a person or the
+    // URI of a file or other rich link, or the URL of an IMAGE formula
+    pub fn chip_link(&self) -> Option<&str> {
+        let chip = self.chip_runs.iter().find_map(|run| {
+            let chip = run.chip.as_ref()?;
+            chip.person_properties
+                .as_ref()
+                .and_then(|p| p.email.as_deref())
+                .or_else(|| chip.rich_link_properties.as_ref()?.uri.as_deref())
+        });
+        chip.or_else(|| {
+            let formula = self.user_entered_value.as_ref()?.formula_value.as_deref()?;
+            image_url(formula)
+        })
+    }
+
+    // link of the cell, or of the first linked run of its text
+    pub fn link(&self) -> Option<&str> {
+        self.hyperlink.as_deref().or_else(|| {
+            self.text_format_runs
+                .iter()
+                .find_map(|run| run.format.link.as_ref()?.uri.as_deref())
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtendedValue {
+    pub formula_value: Option<String>,
+}
+
+// URL of an IMAGE formula given as a string literal, e.g.
+// =IMAGE("https://example.com/a.png", 2)
+fn image_url(formula: &str) -> Option<&str> {
+    let formula = formula.trim().strip_prefix('=')?.trim_start();
+    let (name, args) = formula.split_once('(')?;
+    if !name.trim().eq_ignore_ascii_case("image") {
+        return None;
+    }
+    let (url, _) = args.trim_start().strip_prefix('"')?.split_once('"')?;
+    Some(url)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChipRun {
+    pub chip: Option<Chip>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chip {
+    pub person_properties: Option<PersonProperties>,
+    pub rich_link_properties: Option<RichLinkProperties>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PersonProperties {
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RichLinkProperties {
+    pub uri: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TextFormatRun {
+    #[serde(default)]
+    pub format: TextFormat,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TextFormat {
+    pub link: Option<Link>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Link {
+    pub uri: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DimensionProperties {
+    #[serde(default)]
+    pub developer_metadata: Vec<DeveloperMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeveloperMetadata {
+    pub metadata_id: i64,
+    #[serde(default)]
+    pub metadata_key: String,
+    pub metadata_value: Option<String>,
+    #[serde(default)]
+    pub visibility: String,
+    #[serde(default)]
+    pub location: MetadataLocation,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataLocation {
+    #[serde(default)]
+    pub location_type: String,
+    pub sheet_id: Option<i64>,
+    pub dimension_range: Option<DimensionRange>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DimensionRange {
+    pub sheet_id: i64,
+    #[serde(default)]
+    pub dimension: String,
+    pub start_index: Option<i64>,
+    pub end_index: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtectedRange {
+    pub protected_range_id: i64,
+    #[serde(default)]
+    pub range: GridRange,
+    pub named_range_id: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub warning_only: bool,
+    pub editors: Option<Editors>,
+}
+
+// the editors are only returned to users who can edit the range
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Editors {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub domain_users_can_edit: bool,
+}
+
+// filter view saved in a tab, its criteria are translated to a gviz condition
+// on the sheet columns, the sort order is not applied
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterView {
+    pub filter_view_id: i64,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub range: GridRange,
+    #[serde(default)]
+    pub filter_specs: Vec<FilterSpec>,
+    // criteria keyed by column index, set instead of filter_specs by older
+    // filter views
+    #[serde(default)]
+    pub criteria: HashMap<String, FilterCriteria>,
+}
+
+impl FilterView {
+    // the criteria of all columns as a gviz condition, e.g. "B > 10 and not
+    // (C = 'x')"
+    fn condition(&self) -> Result<Option<String>, FdwError> {
+        let mut specs = Vec::new();
+        for spec in &self.filter_specs {
+            let idx = spec
+                .column_index
+                .ok_or("filter view on data source columns cannot be applied")?;
+            specs.push((idx, &spec.filter_criteria));
+        }
+        for (idx, criteria) in &self.criteria {
+            let idx = idx
+                .parse::<i64>()
+                .map_err(|_| format!("invalid filter view column index '{}'", idx))?;
+            specs.push((idx, criteria));
+        }
+        specs.sort_by_key(|(idx, _)| *idx);
+
+        let mut conds = Vec::new();
+        for (idx, criteria) in specs {
+            conds.extend(criteria.condition(&column_letters(idx))?);
+        }
+        Ok((!conds.is_empty()).then(|| conds.join(" and ")))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterSpec {
+    pub column_index: Option<i64>,
+    #[serde(default)]
+    pub filter_criteria: FilterCriteria,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterCriteria {
+    // displayed values whose rows are hidden
+    #[serde(default)]
+    pub hidden_values: Vec<String>,
+    pub condition: Option<BooleanCondition>,
+    // colour criteria, which have no gviz equivalent
+    pub visible_background_color: Option<JsonValue>,
+    pub visible_background_color_style: Option<JsonValue>,
+    pub visible_foreground_color: Option<JsonValue>,
+    pub visible_foreground_color_style: Option<JsonValue>,
+}
+
+impl FilterCriteria {
+    // the criteria of a column as a gviz condition, None if they are empty
+    fn condition(&self, col: &str) -> Result<Option<String>, FdwError> {
+        if self.visible_background_color.is_some()
+            || self.visible_background_color_style.is_some()
+            || self.visible_foreground_color.is_some()
+            || self.visible_foreground_color_style.is_some()
+        {
+            return Err(format!(
+                "filter view filters column {} by colour, which cannot be applied",
+                col
+            ));
+        }
+
+        let mut conds = Vec::new();
+        // hidden values are matched as numbers if they look like one, as text
+        // otherwise, and a blank value hides empty cells
+        if !self.hidden_values.is_empty() {
+            let values = self
+                .hidden_values
+                .iter()
+                .map(|v| match v.trim().parse::<f64>() {
+                    _ if v.is_empty() => Ok(format!("{} is null", col)),
+                    Ok(n) if n.is_finite() => Ok(format!("{} = {}", col, n)),
+                    _ => Ok(format!("{} = {}", col, gviz_literal(TypeOid::String, v)?)),
+                })
+                .collect::<Result<Vec<_>, FdwError>>()?;
+            conds.push(format!("not ({})", values.join(" or ")));
+        }
+        if let Some(condition) = &self.condition {
+            conds.push(condition.to_gviz(col)?);
+        }
+        Ok((!conds.is_empty()).then(|| conds.join(" and ")))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BooleanCondition {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub values: Vec<ConditionValue>,
+}
+
+impl BooleanCondition {
+    // the condition as a gviz condition on a column, text is compared case
+    // insensitively like the sheet does, dates and formulas are not supported
+    fn to_gviz(&self, col: &str) -> Result<String, FdwError> {
+        let invalid = || format!("invalid value of filter view condition {}", self.kind);
+        let value = |i: usize| match self.values.get(i) {
+            Some(ConditionValue {
+                user_entered_value: Some(v),
+                ..
+            }) => Ok(v.as_str()),
+            _ => Err(invalid()),
+        };
+        let number = |i: usize| match value(i)?.trim().parse::<f64>() {
+            Ok(v) if v.is_finite() => Ok(v.to_string()),
+            _ => Err(invalid()),
+        };
+        let text = |i: usize| gviz_literal(TypeOid::String, &value(i)?.to_lowercase());
+        let compare = |op: &str| Ok::<_, FdwError>(format!("{} {} {}", col, op, number(0)?));
+
+        match self.kind.as_str() {
+            "BLANK" => Ok(format!("{} is null", col)),
+            "NOT_BLANK" => Ok(format!("{} is not null", col)),
+            "NUMBER_GREATER" => compare(">"),
+            "NUMBER_GREATER_THAN_EQ" => compare(">="),
+            "NUMBER_LESS" => compare("<"),
+            "NUMBER_LESS_THAN_EQ" => compare("<="),
+            "NUMBER_EQ" => compare("="),
+            "NUMBER_NOT_EQ" => compare("!="),
+            "NUMBER_BETWEEN" => Ok(format!(
+                "{} >= {} and {} <= {}",
+                col,
+                number(0)?,
+                col,
+                number(1)?
+            )),
+            "NUMBER_NOT_BETWEEN" => Ok(format!(
+                "({} < {} or {} > {})",
+                col,
+                number(0)?,
+                col,
+                number(1)?
+            )),
+            "TEXT_EQ" => Ok(format!("lower({}) = {}", col, text(0)?)),
+            "TEXT_CONTAINS" => Ok(format!("lower({}) contains {}", col, text(0)?)),
+            "TEXT_NOT_CONTAINS" => Ok(format!("not lower({}) contains {}", col, text(0)?)),
+            "TEXT_STARTS_WITH" => Ok(format!("lower({}) starts with {}", col, text(0)?)),
+            "TEXT_ENDS_WITH" => Ok(format!("lower({}) ends with {}", col, text(0)?)),
+            kind => Err(format!(
+                "filter view condition {} on column {} cannot be applied",
+                kind, col
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConditionValue {
+    pub user_entered_value: Option<String>,
+}
+
+// range of a sheet, indexes are 0-based and half open, unbounded if not set
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GridRange {
+    #[serde(default)]
+    pub sheet_id: i64,
+    pub start_row_index: Option<i64>,
+    pub end_row_index: Option<i64>,
+    pub start_column_index: Option<i64>,
+    pub end_column_index: Option<i64>,
+}
+
+impl GridRange {
+    // A1 notation of the range, e.g. "B2:D10", unbounded ends are taken from
+    // the sheet size, or None if the range is the whole sheet
+    pub fn to_a1(&self, grid: &GridProperties) -> Option<String> {
+        if self.start_row_index.is_none()
+            && self.end_row_index.is_none()
+            && self.start_column_index.is_none()
+            && self.end_column_index.is_none()
+        {
+            return None;
+        }
+        Some(format!(
+            "{}{}:{}{}",
+            column_letters(self.start_column_index.unwrap_or(0)),
+            self.start_row_index.unwrap_or(0) + 1,
+            column_letters(self.end_column_index.unwrap_or(grid.column_count) - 1),
+            self.end_row_index.unwrap_or(grid.row_count),
+        ))
+    }
+}
+
+// a page of Drive API file list
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriveFileList {
+    #[serde(default)]
+    pub files: Vec<DriveFile>,
+    pub next_page_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriveFile {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub modified_time: Option<String>,
+    #[serde(default)]
+    pub owners: Vec<DriveUser>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriveUser {
+    pub display_name: Option<String>,
+    pub email_address: Option<String>,
+}
+
+// error response from Google REST API
+#[derive(Debug, Deserialize)]
+pub struct ApiErrorResponse {
+    pub error: ApiError,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiError {
+    #[serde(default)]
+    pub message: String,
+    // canonical error code, e.g. PERMISSION_DENIED or NOT_FOUND
+    #[serde(default)]
+    pub status: String,
+    // ErrorInfo details of Sheets v4 API, e.g. reason RATE_LIMIT_EXCEEDED
+    #[serde(default)]
+    pub details: Vec<ApiErrorReason>,
+    // legacy error list of Drive v3 API, e.g. reason notFound
+    #[serde(default)]
+    pub errors: Vec<ApiErrorReason>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiErrorReason {
+    pub reason: Option<String>,
+    // ErrorInfo metadata, quota errors have quota_metric, quota_limit and
+    // quota_limit_value
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+impl ApiError {
+    // status and reason of the error, e.g. "RESOURCE_EXHAUSTED
+    // RATE_LIMIT_EXCEEDED", whichever of them are given
+    fn reason(&self) -> String {
+        let reason = self
+            .details
+            .iter()
+            .chain(&self.errors)
+            .find_map(|d| d.reason.as_deref());
+        [Some(self.status.as_str()), reason]
+            .into_iter()
+            .flatten()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// describe the quota an error response is about, e.g.
+// "ReadRequestsPerMinutePerUser 60 of sheets.googleapis.com/read_requests"
+pub fn quota_details(body: &str) -> Option<String> {
+    let resp = serde_json::from_str::<ApiErrorResponse>(body).ok()?;
+    resp.error.details.iter().find_map(|d| {
+        let meta = &d.metadata;
+        let limit = meta.get("quota_limit")?;
+        Some(format!(
+            "{} {} of {}",
+            limit,
+            meta.get("quota_limit_value").map_or("?", String::as_str),
+            meta.get("quota_metric").map_or("?", String::as_str)
+        ))
+    })
+}
+
+// make the message of a Google API error response, with the status and reason
+// of a structured error payload so quota problems can be told from sharing ones
+pub fn api_error_message(status_code: u16, body: &str) -> String {
+    match serde_json::from_str::<ApiErrorResponse>(body) {
+        Ok(resp) => match resp.error.reason() {
+            reason if reason.is_empty() => {
+                format!("Google API error {}: {}", status_code, resp.error.message)
+            }
+            reason => format!(
+                "Google API error {} {}: {}",
+                status_code, reason, resp.error.message
+            ),
+        },
+        // the body is not shown, it is often a whole HTML error page
+        Err(_) => format!(
+            "Google API error {}: {}",
+            status_code,
+            status_reason(status_code)
+        ),
+    }
+}
+
+// reason phrase of an HTTP status code
+pub fn status_reason(status_code: u16) -> &'static str {
+    match status_code {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ if (400..500).contains(&status_code) => "client error",
+        _ if (500..600).contains(&status_code) => "server error",
+        _ => "unexpected response",
+    }
+}
+
+// a key is revoked or out of quota, so the request may succeed with another
+// key, errors about the spreadsheet itself are the same with any key
+pub fn is_key_error(status_code: u16, body: &str) -> bool {
+    const REASONS: &[&str] = &[
+        "API_KEY_INVALID",
+        "API_KEY_EXPIRED",
+        "API_KEY_SERVICE_BLOCKED",
+        "RATE_LIMIT_EXCEEDED",
+        "keyInvalid",
+        "keyExpired",
+        "rateLimitExceeded",
+        "userRateLimitExceeded",
+        "dailyLimitExceeded",
+        "quotaExceeded",
+    ];
+    status_code == 429
+        || serde_json::from_str::<ApiErrorResponse>(body).is_ok_and(|resp| {
+            let error = resp.error;
+            error
+                .details
+                .iter()
+                .chain(&error.errors)
+                .filter_map(|d| d.reason.as_deref())
+                .any(|reason| REASONS.contains(&reason))
+        })
+}
+
+// make a request to Google REST API, Sheets v4 or Drive v3, and parse the
+// JSON response, the API keys are tried in turn until one is not revoked or
+// out of quota, and the working one is used first from then on
+pub fn fetch_api<T: DeserializeOwned>(url: &str) -> Result<T, FdwError> {
+    let keys = api_keys().clone();
+    let mut last_err = None;
+    for (idx, key) in keys.iter().enumerate() {
+        let headers: Vec<(String, String)> =
+            vec![("user-agent".to_owned(), "Sheets FDW".to_owned())];
+
+        let req = http::Request {
+            method: http::Method::Get,
+            url: format!("{}&key={}", url, encode_query(key)),
+            headers,
+            body: String::default(),
+        };
+        let resp = retry::get(&req)?;
+        if !(200..300).contains(&resp.status_code) {
+            let err = api_error_message(resp.status_code, &resp.body);
+            if !is_key_error(resp.status_code, &resp.body) {
+                return Err(err);
+            }
+            last_err = Some(err);
+            continue;
+        }
+        if idx > 0 {
+            api_keys().rotate_left(idx);
+        }
+        return serde_json::from_str(&resp.body).map_err(|e| e.to_string());
+    }
+    Err(last_err.unwrap_or("api_key or api_key_id is required".to_owned()))
+}
+
+// make a request to Sheets v4 API for spreadsheet metadata
+pub fn fetch_spreadsheet(url: &str) -> Result<Spreadsheet, FdwError> {
+    fetch_api(url)
+}
+
+// list files through Drive API, following all the result pages
+pub fn fetch_drive_files(url: &str) -> Result<Vec<DriveFile>, FdwError> {
+    let mut files = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let page_url = match &page_token {
+            Some(token) => format!("{}&pageToken={}", url, encode_query(token)),
+            None => url.to_owned(),
+        };
+        let page: DriveFileList = fetch_api(&page_url)?;
+        files.extend(page.files);
+        page_token = page.next_page_token;
+        if page_token.is_none() {
+            return Ok(files);
+        }
+    }
+}
+
+// API keys of the scan, the first one is used until it fails
+static API_KEYS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn api_keys() -> MutexGuard<'static, Vec<String>> {
+    API_KEYS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// forget the API keys of a previous scan
+pub fn reset_api_keys() {
+    api_keys().clear();
+}
+
+// load the API keys from server options, given directly by api_key and the
+// api_keys list or as Vault secrets by api_key_id and the api_key_ids list,
+// more than one key lets a revoked or exhausted key be replaced without
+// downtime, they are loaded once for all requests of a scan
+pub fn load_api_keys(opts: &Options) -> FdwResult {
+    if !api_keys().is_empty() {
+        return Ok(());
+    }
+    let list = |key: &str| {
+        opts.get(key)
+            .map(|v| parse_list_option(&v))
+            .unwrap_or_default()
+    };
+
+    let mut keys: Vec<String> = opts.get("api_key").into_iter().collect();
+    keys.extend(list("api_keys"));
+    let key_ids = opts
+        .get("api_key_id")
+        .into_iter()
+        .chain(list("api_key_ids"));
+    for key_id in key_ids {
+        keys.push(
+            utils::get_vault_secret(&key_id)
+                .ok_or(format!("cannot get api_key from Vault secret '{}'", key_id))?,
+        );
+    }
+    if keys.is_empty() {
+        return Err("api_key or api_key_id is required for spreadsheet objects".to_owned());
+    }
+    *api_keys() = keys;
+    Ok(())
+}
@@ -0,0 +1,491 @@
+// OAuth access tokens of the write requests, API keys can only read public
+// data, so spreadsheets are modified as a service account whose JSON key is
+// kept in Vault under the secret named by the service_account_key_id server
+// option, the host signs the JWT of the key and the token URI of the key
+// exchanges it for an access token, which is kept for the rest of the
+// modify
+//
+// the secret can instead hold the external_account credentials of workload
+// identity federation, whose subject token is read from a URL, exchanged for
+// a federated token by Google STS and, if the credentials say so, for the
+// token of an impersonated service account
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use serde::Deserialize;
+
+use crate::api::api_error_message;
+use crate::bindings::supabase::wrappers::{
+    http, jwt,
+    types::{FdwError, FdwResult, Options},
+    utils,
+};
+use crate::gviz::encode_query;
+use crate::metrics::{self, Counter};
+use crate::ratelimit;
+
+// scope of the tokens, reading and writing spreadsheets
+pub const SHEETS_SCOPE: &str = "https://www.googleapis.com/auth/spreadsheets";
+
+// scope of the tokens reading the Drive capabilities of the spreadsheet, to
+// check it can be edited before it is modified
+pub const DRIVE_METADATA_SCOPE: &str = "https://www.googleapis.com/auth/drive.metadata.readonly";
+
+// scopes the tokens are requested for
+pub const TOKEN_SCOPES: [&str; 2] = [SHEETS_SCOPE, DRIVE_METADATA_SCOPE];
+
+// scopes a token can write spreadsheets with
+const WRITE_SCOPES: [&str; 3] = [
+    SHEETS_SCOPE,
+    "https://www.googleapis.com/auth/drive",
+    "https://www.googleapis.com/auth/drive.file",
+];
+
+// endpoint describing an access token, to check its scopes
+const TOKENINFO_URL: &str = "https://oauth2.googleapis.com/tokeninfo";
+
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+// scope of a federated token used to impersonate a service account
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+// hours the signed JWT is valid, the longest Google accepts
+const JWT_TTL_HOURS: u32 = 1;
+
+// JSON key of a service account, as downloaded from the Cloud console, only
+// the fields used to get a token
+#[derive(Debug, Deserialize)]
+pub struct ServiceAccountKey {
+    pub client_email: String,
+    pub private_key: String,
+    #[serde(default = "default_token_uri")]
+    pub token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_owned()
+}
+
+impl ServiceAccountKey {
+    // claims of the JWT asking for a token of the scope, the host adds its
+    // issue and expiry times
+    pub fn claims(&self, scope: &str) -> Vec<(String, String)> {
+        vec![
+            ("iss".to_owned(), self.client_email.clone()),
+            ("scope".to_owned(), scope.to_owned()),
+            ("aud".to_owned(), self.token_uri.clone()),
+        ]
+    }
+
+    // request exchanging a signed JWT for an access token
+    pub fn token_request(&self, assertion: &str) -> http::Request {
+        http::Request {
+            method: http::Method::Post,
+            url: self.token_uri.clone(),
+            headers: vec![(
+                "content-type".to_owned(),
+                "application/x-www-form-urlencoded".to_owned(),
+            )],
+            body: format!(
+                "grant_type={}&assertion={}",
+                encode_query(JWT_BEARER_GRANT),
+                encode_query(assertion)
+            ),
+        }
+    }
+}
+
+// external_account credentials of workload identity federation, only the
+// URL credential source can be read by the guest, which has no files or
+// processes
+#[derive(Debug, Deserialize)]
+pub struct ExternalAccount {
+    pub audience: String,
+    pub subject_token_type: String,
+    #[serde(default = "default_sts_url")]
+    pub token_url: String,
+    pub service_account_impersonation_url: Option<String>,
+    pub credential_source: CredentialSource,
+}
+
+fn default_sts_url() -> String {
+    "https://sts.googleapis.com/v1/token".to_owned()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CredentialSource {
+    pub url: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub format: Option<CredentialFormat>,
+}
+
+// format of the subject token response, text if not given
+#[derive(Debug, Deserialize)]
+pub struct CredentialFormat {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub subject_token_field_name: Option<String>,
+}
+
+impl ExternalAccount {
+    // request of the subject token from the URL of the credential source
+    pub fn subject_token_request(&self) -> Result<http::Request, FdwError> {
+        let url = self.credential_source.url.clone().ok_or(
+            "external_account credentials need a url credential_source, files and \
+             executables cannot be read",
+        )?;
+        let mut headers: Vec<(String, String)> = self
+            .credential_source
+            .headers
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        headers.sort();
+        Ok(http::Request {
+            method: http::Method::Get,
+            url,
+            headers,
+            body: String::default(),
+        })
+    }
+
+    // get the subject token from the response of the credential source
+    pub fn subject_token(&self, resp: &http::Response) -> Result<String, FdwError> {
+        if !(200..300).contains(&resp.status_code) {
+            return Err(format!(
+                "cannot get the subject token: {}",
+                api_error_message(resp.status_code, &resp.body)
+            ));
+        }
+        let format = self.credential_source.format.as_ref();
+        let token = match format.map(|f| (f.kind.as_str(), &f.subject_token_field_name)) {
+            None | Some(("text", _)) => Some(resp.body.trim().to_owned()),
+            Some(("json", Some(field))) => serde_json::from_str::<serde_json::Value>(&resp.body)
+                .ok()
+                .and_then(|v| v.get(field)?.as_str().map(str::to_owned)),
+            Some((kind, _)) => {
+                return Err(format!("invalid credential_source format '{}'", kind));
+            }
+        };
+        token
+            .filter(|token| !token.is_empty())
+            .ok_or("credential source returned no subject token".to_owned())
+    }
+
+    // request exchanging the subject token for a federated token, of the
+    // token scopes or, to impersonate a service account, of the cloud
+    // platform scope
+    pub fn sts_request(&self, subject_token: &str) -> http::Request {
+        let scope = match self.service_account_impersonation_url {
+            Some(_) => CLOUD_PLATFORM_SCOPE.to_owned(),
+            None => TOKEN_SCOPES.join(" "),
+        };
+        let params = [
+            ("grant_type", TOKEN_EXCHANGE_GRANT),
+            ("audience", &self.audience),
+            ("scope", &scope),
+            ("requested_token_type", ACCESS_TOKEN_TYPE),
+            ("subject_token", subject_token),
+            ("subject_token_type", &self.subject_token_type),
+        ];
+        http::Request {
+            method: http::Method::Post,
+            url: self.token_url.clone(),
+            headers: vec![(
+                "content-type".to_owned(),
+                "application/x-www-form-urlencoded".to_owned(),
+            )],
+            body: params
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, encode_query(v)))
+                .collect::<Vec<_>>()
+                .join("&"),
+        }
+    }
+
+    // request of the token of the impersonated service account, None if the
+    // federated token is used as is
+    pub fn impersonation_request(&self, federated_token: &str) -> Option<http::Request> {
+        let url = self.service_account_impersonation_url.clone()?;
+        Some(http::Request {
+            method: http::Method::Post,
+            url,
+            headers: vec![
+                ("content-type".to_owned(), "application/json".to_owned()),
+                (
+                    "authorization".to_owned(),
+                    format!("Bearer {}", federated_token),
+                ),
+            ],
+            body: serde_json::json!({ "scope": TOKEN_SCOPES }).to_string(),
+        })
+    }
+}
+
+// credentials the access tokens are got with, by the type of their JSON
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credential {
+    ServiceAccount(ServiceAccountKey),
+    ExternalAccount(ExternalAccount),
+}
+
+impl Credential {
+    // email of the service account, or the audience of a federated token
+    // used as is
+    pub fn identity(&self) -> String {
+        match self {
+            Self::ServiceAccount(key) => key.client_email.clone(),
+            Self::ExternalAccount(account) => account
+                .service_account_impersonation_url
+                .as_deref()
+                .and_then(|url| url.rsplit_once("/serviceAccounts/"))
+                .map(|(_, email)| email.trim_end_matches(":generateAccessToken").to_owned())
+                .unwrap_or_else(|| account.audience.clone()),
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<Self, FdwError> {
+        serde_json::from_str(json).map_err(|e| {
+            format!(
+                "invalid credentials, expect a service_account key or external_account \
+                 credentials: {}",
+                e
+            )
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+}
+
+// error response of an OAuth token endpoint
+#[derive(Debug, Deserialize)]
+struct OAuthError {
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
+
+// get the access token from a token endpoint response
+pub fn parse_token(resp: &http::Response) -> Result<TokenResponse, FdwError> {
+    if !(200..300).contains(&resp.status_code) {
+        return Err(match serde_json::from_str::<OAuthError>(&resp.body) {
+            Ok(e) => format!(
+                "cannot get an access token (HTTP {}): {} {}",
+                resp.status_code, e.error, e.error_description
+            )
+            .trim_end()
+            .to_owned(),
+            Err(_) => format!(
+                "cannot get an access token: {}",
+                api_error_message(resp.status_code, &resp.body)
+            ),
+        });
+    }
+    serde_json::from_str(&resp.body).map_err(|e| format!("invalid token response: {}", e))
+}
+
+// response of generateAccessToken of an impersonated service account
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImpersonatedToken {
+    access_token: String,
+}
+
+// get the access token of the impersonated service account
+pub fn parse_impersonated_token(resp: &http::Response) -> Result<String, FdwError> {
+    if !(200..300).contains(&resp.status_code) {
+        return Err(format!(
+            "cannot impersonate the service account: {}",
+            api_error_message(resp.status_code, &resp.body)
+        ));
+    }
+    serde_json::from_str::<ImpersonatedToken>(&resp.body)
+        .map(|token| token.access_token)
+        .map_err(|e| format!("invalid impersonation response: {}", e))
+}
+
+// description of an access token by the tokeninfo endpoint
+#[derive(Debug, Deserialize)]
+struct TokenInfo {
+    #[serde(default)]
+    scope: String,
+}
+
+// request of the description of an access token, sent in the body so the
+// token is not in a URL
+pub fn tokeninfo_request(token: &str) -> http::Request {
+    http::Request {
+        method: http::Method::Post,
+        url: TOKENINFO_URL.to_owned(),
+        headers: vec![(
+            "content-type".to_owned(),
+            "application/x-www-form-urlencoded".to_owned(),
+        )],
+        body: format!("access_token={}", encode_query(token)),
+    }
+}
+
+// check from its tokeninfo response that the token can write spreadsheets
+pub fn check_write_scope(resp: &http::Response) -> FdwResult {
+    if !(200..300).contains(&resp.status_code) {
+        return Err(format!(
+            "cannot check the access token: {}",
+            api_error_message(resp.status_code, &resp.body)
+        ));
+    }
+    let info: TokenInfo = serde_json::from_str(&resp.body)
+        .map_err(|e| format!("invalid tokeninfo response: {}", e))?;
+    match info
+        .scope
+        .split(' ')
+        .any(|scope| WRITE_SCOPES.contains(&scope))
+    {
+        true => Ok(()),
+        false => Err(format!(
+            "the access token cannot write spreadsheets, its scopes are '{}'",
+            info.scope
+        )),
+    }
+}
+
+// check the access token can write spreadsheets
+pub fn check_token() -> FdwResult {
+    let token = access_token()?;
+    check_write_scope(&token_call(&tokeninfo_request(&token))?)
+}
+
+// key and token of the modify
+#[derive(Debug, Default)]
+struct Credentials {
+    key: Option<Credential>,
+    token: Option<String>,
+}
+
+static CREDENTIALS: Mutex<Credentials> = Mutex::new(Credentials {
+    key: None,
+    token: None,
+});
+
+fn credentials() -> MutexGuard<'static, Credentials> {
+    CREDENTIALS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// load the service account key from Vault, dropping the token of a previous
+// modify
+pub fn configure(server_opts: &Options) -> FdwResult {
+    let key_id = server_opts
+        .get("service_account_key_id")
+        .ok_or("service_account_key_id is required to modify spreadsheets")?;
+    let key = utils::get_vault_secret(&key_id).ok_or(format!(
+        "cannot get service account key from Vault secret '{}'",
+        key_id
+    ))?;
+    *credentials() = Credentials {
+        key: Some(Credential::parse(&key)?),
+        token: None,
+    };
+    Ok(())
+}
+
+// identity the spreadsheets are modified as, for the audit records
+pub fn identity() -> String {
+    credentials()
+        .key
+        .as_ref()
+        .map(Credential::identity)
+        .unwrap_or_default()
+}
+
+// get the access token, which is requested the first time
+pub fn access_token() -> Result<String, FdwError> {
+    let mut creds = credentials();
+    if let Some(token) = &creds.token {
+        return Ok(token.clone());
+    }
+    let token = match &creds.key {
+        Some(Credential::ServiceAccount(key)) => {
+            let assertion = jwt::encode(
+                &key.claims(&TOKEN_SCOPES.join(" ")),
+                "RS256",
+                &key.private_key,
+                JWT_TTL_HOURS,
+            )
+            .map_err(|e| format!("cannot sign the service account JWT: {}", e))?;
+            parse_token(&token_call(&key.token_request(&assertion))?)?.access_token
+        }
+        Some(Credential::ExternalAccount(account)) => {
+            let resp = token_call(&account.subject_token_request()?)?;
+            let subject_token = account.subject_token(&resp)?;
+            let federated = parse_token(&token_call(&account.sts_request(&subject_token))?)?;
+            match account.impersonation_request(&federated.access_token) {
+                Some(req) => parse_impersonated_token(&token_call(&req)?)?,
+                None => federated.access_token,
+            }
+        }
+        None => return Err("service_account_key_id is required to modify spreadsheets".to_owned()),
+    };
+    creds.token = Some(token.clone());
+    Ok(token)
+}
+
+// make a request of the token flow
+fn token_call(req: &http::Request) -> Result<http::Response, FdwError> {
+    ratelimit::acquire()?;
+    let resp = match req.method {
+        http::Method::Get => http::get(req),
+        _ => http::post(req),
+    };
+    metrics::add(Counter::Requests, 1);
+    resp
+}
+
+// send a request with the access token, a token expired during a long
+// modify is refused with HTTP 401, so it is refreshed and the request sent
+// once more
+pub fn send(
+    req: &http::Request,
+    send: impl FnMut(&http::Request) -> Result<http::Response, FdwError>,
+) -> Result<http::Response, FdwError> {
+    with_token(
+        req,
+        |refresh| {
+            if refresh {
+                credentials().token = None;
+            }
+            access_token()
+        },
+        send,
+    )
+}
+
+// send a request with the token given by a getter, which is asked for a new
+// token after a 401
+pub fn with_token(
+    req: &http::Request,
+    mut token: impl FnMut(bool) -> Result<String, FdwError>,
+    mut send: impl FnMut(&http::Request) -> Result<http::Response, FdwError>,
+) -> Result<http::Response, FdwError> {
+    let mut req = req.clone();
+    for refresh in [false, true] {
+        req.headers
+            .retain(|(k, _)| !k.eq_ignore_ascii_case("authorization"));
+        req.headers.push((
+            "authorization".to_owned(),
+            format!("Bearer {}", token(refresh)?),
+        ));
+        let resp = send(&req)?;
+        if resp.status_code != 401 || refresh {
+            return Ok(resp);
+        }
+    }
+    unreachable!("the request is sent at most twice")
+}
@@ -0,0 +1,166 @@
+// circuit breaker of remote failures, after breaker_failures consecutive
+// requests to the same endpoint fail within breaker_window_secs, requests to
+// it fail fast for breaker_open_secs instead of going through their retries
+// and backoff again, the circuits are kept in the stats metadata as each scan
+// runs in a new instance, they are read when the scan starts and written back
+// with the metrics when it ends, without a lock, so the breaker is approximate,
+// scans running at the same time miss each other's failures and the last one
+// to end wins, so more than breaker_failures requests may fail before the
+// circuit opens
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use crate::bindings::supabase::wrappers::{
+    time,
+    types::{FdwResult, Options},
+};
+use crate::metrics;
+
+// section of the stats metadata the circuits are kept in
+const SECTION: &str = "circuit_breaker";
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    // consecutive failed requests opening the circuit
+    pub failures: u32,
+    pub window_secs: i64,
+    pub open_secs: i64,
+}
+
+// state of the circuit of an endpoint
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Circuit {
+    // consecutive failed requests since the first of them
+    pub failures: u32,
+    // epoch seconds of the first of the failed requests
+    pub since: i64,
+    // epoch seconds until which requests fail fast
+    pub open_until: i64,
+}
+
+impl Circuit {
+    // seconds until the circuit closes, None if it is closed
+    pub fn open_for(&self, now: i64) -> Option<i64> {
+        (self.open_until > now).then(|| self.open_until - now)
+    }
+
+    // count the outcome of a request, a success closes the circuit and the
+    // failures opening it start over past the window
+    pub fn record(&mut self, failed: bool, now: i64, config: &BreakerConfig) {
+        if !failed {
+            *self = Self::default();
+            return;
+        }
+        if self.failures == 0 || now - self.since > config.window_secs {
+            self.failures = 0;
+            self.since = now;
+        }
+        self.failures += 1;
+        if self.failures >= config.failures {
+            self.failures = 0;
+            self.open_until = now + config.open_secs;
+        }
+    }
+
+    // the circuit has nothing to remember
+    fn is_idle(&self, now: i64, config: &BreakerConfig) -> bool {
+        self.open_for(now).is_none()
+            && (self.failures == 0 || now - self.since > config.window_secs)
+    }
+}
+
+static CONFIG: Mutex<Option<BreakerConfig>> = Mutex::new(None);
+
+// circuits by endpoint, loaded once per scan
+static CIRCUITS: Mutex<BTreeMap<String, Circuit>> = Mutex::new(BTreeMap::new());
+
+fn config() -> MutexGuard<'static, Option<BreakerConfig>> {
+    CONFIG.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn circuits_mut() -> MutexGuard<'static, BTreeMap<String, Circuit>> {
+    CIRCUITS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// set up the breaker from the server options and load the circuits, no
+// breaker if breaker_failures is not set
+pub fn configure(server_opts: &Options) -> FdwResult {
+    let secs = |key: &str| match server_opts.get(key) {
+        Some(secs) => match secs.parse::<i64>() {
+            Ok(secs) if secs > 0 => Ok(secs),
+            _ => Err(format!("invalid {} '{}'", key, secs)),
+        },
+        None => Ok(60),
+    };
+    *config() = match server_opts.get("breaker_failures") {
+        Some(failures) => match failures.parse::<u32>() {
+            Ok(n) if n > 0 => Some(BreakerConfig {
+                failures: n,
+                window_secs: secs("breaker_window_secs")?,
+                open_secs: secs("breaker_open_secs")?,
+            }),
+            _ => return Err(format!("invalid breaker_failures '{}'", failures)),
+        },
+        None => None,
+    };
+    *circuits_mut() = match config().is_some() {
+        true => circuits(metrics::get_section(SECTION)),
+        false => BTreeMap::new(),
+    };
+    Ok(())
+}
+
+// endpoint of a request URL, without its query which has the API key
+fn endpoint(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(endpoint, _)| endpoint)
+}
+
+// fail fast if the circuit of the endpoint of a request is open
+pub fn check(url: &str) -> FdwResult {
+    if config().is_none() {
+        return Ok(());
+    }
+    let endpoint = endpoint(url);
+    let circuit = circuits_mut().get(endpoint).copied().unwrap_or_default();
+    match circuit.open_for(time::epoch_secs()) {
+        Some(secs) => Err(format!(
+            "circuit open for {} after repeated failed requests, retry after {} s",
+            endpoint, secs
+        )),
+        None => Ok(()),
+    }
+}
+
+// count the outcome of a request, after its retries, in the circuit of its
+// endpoint
+pub fn record(url: &str, failed: bool) {
+    let Some(config) = *config() else {
+        return;
+    };
+    let endpoint = endpoint(url);
+    let mut circuits = circuits_mut();
+    if !failed && !circuits.contains_key(endpoint) {
+        return;
+    }
+    let now = time::epoch_secs();
+    circuits
+        .entry(endpoint.to_owned())
+        .or_default()
+        .record(failed, now, &config);
+    circuits.retain(|_, circuit| !circuit.is_idle(now, &config));
+    metrics::set_section(SECTION, serde_json::json!(*circuits));
+}
+
+// circuits of the stats metadata section by endpoint, entries which are not
+// circuits are dropped
+pub fn circuits(section: Option<JsonValue>) -> BTreeMap<String, Circuit> {
+    let Some(JsonValue::Object(map)) = section else {
+        return BTreeMap::new();
+    };
+    map.into_iter()
+        .filter_map(|(k, v)| Some((k, serde_json::from_value(v).ok()?)))
+        .collect()
+}
@@ -0,0 +1,1237 @@
+// target columns and the conversion of gviz cells to target cells
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+use crate::api::CellData;
+use crate::bindings::supabase::wrappers::{
+    types::{Cell, Column, FdwError, FdwResult, TypeOid},
+    utils,
+};
+use crate::datetime::{parse_datetime, parse_gviz_date, TimeZone, MICROS_PER_SEC, ZONE_RULES};
+use crate::gviz::GvizCell;
+use crate::options::{get_column_option, parse_bool_option, parse_list_option, OptionLookup};
+use crate::ScanSource;
+
+// how source cells are coerced into target column types
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Coercion {
+    // only convert cells whose value already has the target type
+    #[default]
+    Default,
+    // error on cells whose value doesn't have the target type
+    Strict,
+    // best-effort conversion, e.g. "1,234" to number or "TRUE" to boolean,
+    // cells which cannot be converted are NULL
+    Lenient,
+}
+
+// what float columns get for non-finite values, such as "#DIV/0!" error cells
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NonFinite {
+    #[default]
+    Null,
+    Nan,
+    Error,
+}
+
+// what a NULL written to a sheet cell becomes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NullWrite {
+    // the cell is cleared
+    #[default]
+    Clear,
+    // the cell is left as it is, so an update doesn't change it
+    Keep,
+    // the first of null_values is written, which reads back as NULL
+    Sentinel,
+}
+
+// how line breaks inside text cells are returned, they break CSV exports
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Newlines {
+    // as they are in the sheet
+    #[default]
+    Keep,
+    // each line break, CRLF included, is replaced with a space
+    Space,
+    // each line break is escaped as "\n"
+    Escape,
+    // the lines as a JSON array of strings, for jsonb columns as the host has
+    // no array cells
+    Split,
+}
+
+impl Newlines {
+    // apply the policy to a cell text, except splitting
+    fn normalize(&self, s: String) -> String {
+        let sep = match self {
+            Self::Keep | Self::Split => return s,
+            Self::Space => " ",
+            Self::Escape => "\\n",
+        };
+        if !s.contains(['\n', '\r']) {
+            return s;
+        }
+        s.replace("\r\n", "\n").replace(['\n', '\r'], sep)
+    }
+}
+
+// lines of a cell text, split at LF, CRLF or CR line breaks
+fn split_lines(s: &str) -> Vec<&str> {
+    s.split("\r\n")
+        .flat_map(|l| l.split(['\n', '\r']))
+        .collect()
+}
+
+// how cells of a column holding personal data are masked, their raw values
+// are kept out of errors and warnings too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mask {
+    // SHA-256 of the text as hex, still usable for joins and counts, keyed
+    // as HMAC-SHA-256 if mask_key_id is set so values cannot be guessed from
+    // their hashes
+    Hash,
+    // only the last 4 characters are kept, the others are replaced with '*',
+    // and nothing is kept of shorter values
+    Partial,
+    // always NULL, the cell is not even converted
+    Null,
+}
+
+impl Mask {
+    // mask the text of a converted cell, hashed with the key if given
+    pub fn apply(&self, v: &str, key: Option<&[u8]>) -> Option<String> {
+        match self {
+            Self::Hash => Some(match key {
+                Some(key) => hmac_sha256_hex(key, v.as_bytes()),
+                None => sha256_hex(v.as_bytes()),
+            }),
+            // values of 4 characters or fewer, e.g. a PIN, are masked whole
+            Self::Partial => {
+                let len = v.chars().count();
+                let kept = if len > 4 { 4 } else { 0 };
+                Some(
+                    v.chars()
+                        .enumerate()
+                        .map(|(i, c)| if i + kept < len { '*' } else { c })
+                        .collect(),
+                )
+            }
+            Self::Null => None,
+        }
+    }
+}
+
+// SHA-256 digest of data as lowercase hex
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// HMAC-SHA-256 of data with a key as lowercase hex
+pub fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+// target column of a scan, resolved once in begin_scan
+#[derive(Debug)]
+pub struct ColumnSpec {
+    pub name: String,
+    // index of the source cell in a gviz row
+    pub src_idx: usize,
+    pub type_oid: TypeOid,
+    // strip leading and trailing whitespace from string cells
+    pub trim: bool,
+    // convert empty string cells to NULL
+    pub empty_as_null: bool,
+    // placeholder strings converted to NULL, e.g. "N/A"
+    pub null_values: Vec<String>,
+    // permitted cell values, e.g. mirroring a data validation dropdown
+    pub allowed_values: Option<Vec<String>>,
+    // decimal separator used by formatted numbers
+    pub decimal_separator: char,
+    pub coercion: Coercion,
+    pub non_finite: NonFinite,
+    // strftime-like format of date and time text cells, e.g. '%d/%m/%Y'
+    pub date_format: Option<String>,
+    // time zone of naive sheet datetimes converted to timestamptz, UTC if
+    // not set
+    pub timezone: Option<TimeZone>,
+    // set if the column is not read from source cells
+    pub virtual_col: Option<VirtualColumn>,
+    // gviz expression computing the column remotely, e.g. 'B*C'
+    pub expr: Option<String>,
+    // pattern extracting part of string cells before conversion
+    pub extract_regex: Option<Regex>,
+    // JSON pointer of the cell in a REST source row, "/<column>" by default
+    pub json_path: String,
+    // masking of personal data, so the raw value never reaches Postgres
+    pub mask: Option<Mask>,
+    // text written starting with = is a formula rather than a literal
+    pub formula: bool,
+    pub null_write: NullWrite,
+    // HMAC key of hash masking, read from the Vault secret of mask_key_id
+    pub mask_key: Option<String>,
+    // cell of empty and missing source cells, NULL if not set
+    pub default: Option<Cell>,
+    pub newlines: Newlines,
+    // column whose sheet cells the cell data column reads, its src_idx is
+    // resolved in begin_scan
+    pub cell_of: Option<String>,
+}
+
+// target columns filled by the scan itself, named with a leading underscore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtualColumn {
+    // id of the spreadsheet the row is read from
+    SpreadsheetId,
+    // SHA-256 of the other cells of the row, for change detection
+    RowHash,
+    // 1-based number of the sheet row the row is read from, the rowid of
+    // updates
+    RowNumber,
+    // sheet row and SHA-256 of the displayed cells of the row, the rowid of
+    // updates checked against concurrent edits
+    RowVersion,
+    // property of the sheet cells of another column, set by the cell_data
+    // option rather than the name
+    CellData(CellProperty),
+}
+
+impl VirtualColumn {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "_spreadsheet_id" => Some(Self::SpreadsheetId),
+            "_row_hash" => Some(Self::RowHash),
+            "_row_number" => Some(Self::RowNumber),
+            "_row_version" => Some(Self::RowVersion),
+            _ => None,
+        }
+    }
+
+    pub fn type_oid(&self) -> TypeOid {
+        match self {
+            Self::SpreadsheetId | Self::RowHash | Self::RowVersion => TypeOid::String,
+            Self::RowNumber => TypeOid::I64,
+            Self::CellData(prop) => prop.type_oid(),
+        }
+    }
+
+    pub fn accepts(&self, type_oid: TypeOid) -> bool {
+        match self {
+            Self::SpreadsheetId | Self::RowHash | Self::RowVersion => {
+                matches!(type_oid, TypeOid::String)
+            }
+            Self::RowNumber => matches!(type_oid, TypeOid::I64),
+            Self::CellData(prop) => prop.accepts(type_oid),
+        }
+    }
+
+    // cell of the virtual column, the row hash is set once the other cells
+    // of the row are converted, the row number and cell data come with the
+    // source row
+    pub fn cell(&self, source: &ScanSource) -> Option<Cell> {
+        match self {
+            Self::SpreadsheetId => Some(Cell::String(source.spread_sheet_id.clone())),
+            Self::RowHash | Self::RowNumber | Self::RowVersion | Self::CellData(_) => None,
+        }
+    }
+
+    // the column is read by the sheet row of each row
+    pub fn numbers_rows(&self) -> bool {
+        matches!(self, Self::RowNumber | Self::RowVersion)
+    }
+}
+
+// property of a sheet cell read from the Sheets v4 grid data, which gviz
+// doesn't return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellProperty {
+    // text of the note attached to the cell
+    Note,
+    // target of the link of the cell, given by a HYPERLINK formula or a
+    // linked run of its text
+    Hyperlink,
+    // text of the formula of the cell, e.g. "=SUM(B2:B10)"
+    Formula,
+    // background color and text format of the cell as displayed, as JSON
+    Format,
+    // background color of the cell as displayed, e.g. "#ff0000"
+    Background,
+    // link of a smart chip or an IMAGE formula of the cell, whose value gviz
+    // returns as the chip text or as empty
+    Chip,
+}
+
+impl CellProperty {
+    pub fn parse(v: &str) -> Result<Self, FdwError> {
+        match v {
+            "note" => Ok(Self::Note),
+            "hyperlink" => Ok(Self::Hyperlink),
+            "formula" => Ok(Self::Formula),
+            "format" => Ok(Self::Format),
+            "background" => Ok(Self::Background),
+            "chip" => Ok(Self::Chip),
+            _ => Err(format!(
+                "invalid cell_data '{}', expect 'note', 'hyperlink', 'formula', 'format', \
+                 'background' or 'chip'",
+                v
+            )),
+        }
+    }
+
+    // field mask of the property in the cell data
+    pub fn fields(&self) -> &'static str {
+        match self {
+            Self::Note => "note",
+            Self::Hyperlink => "hyperlink,textFormatRuns.format.link",
+            Self::Formula => "userEnteredValue.formulaValue",
+            Self::Format => "effectiveFormat(backgroundColor,textFormat)",
+            Self::Background => "effectiveFormat.backgroundColor",
+            Self::Chip => "chipRuns.chip,userEnteredValue.formulaValue",
+        }
+    }
+
+    pub fn type_oid(&self) -> TypeOid {
+        match self {
+            Self::Note | Self::Hyperlink | Self::Formula | Self::Background | Self::Chip => {
+                TypeOid::String
+            }
+            Self::Format => TypeOid::Json,
+        }
+    }
+
+    pub fn accepts(&self, type_oid: TypeOid) -> bool {
+        match self {
+            Self::Note | Self::Hyperlink | Self::Formula | Self::Background | Self::Chip => {
+                matches!(type_oid, TypeOid::String)
+            }
+            Self::Format => matches!(type_oid, TypeOid::Json),
+        }
+    }
+
+    // cell of the property, NULL if the cell doesn't have it
+    pub fn cell(&self, data: &CellData) -> Option<Cell> {
+        match self {
+            Self::Note => data.note.clone().map(Cell::String),
+            Self::Hyperlink => data.link().map(|link| Cell::String(link.to_owned())),
+            Self::Formula => data
+                .user_entered_value
+                .as_ref()
+                .and_then(|v| v.formula_value.clone())
+                .map(Cell::String),
+            Self::Format => data
+                .effective_format
+                .as_ref()
+                .map(|format| Cell::Json(format.to_string())),
+            Self::Background => data.background().map(Cell::String),
+            Self::Chip => data.chip_link().map(|link| Cell::String(link.to_owned())),
+        }
+    }
+}
+
+// point the cell data columns at the sheet cells of the columns given by
+// their cell_of option
+pub fn resolve_cell_of(tgt_cols: &mut [ColumnSpec]) -> FdwResult {
+    for idx in 0..tgt_cols.len() {
+        let Some(cell_of) = &tgt_cols[idx].cell_of else {
+            continue;
+        };
+        let src_idx = tgt_cols
+            .iter()
+            .find(|c| &c.name == cell_of && c.virtual_col.is_none())
+            .map(|c| c.src_idx)
+            .ok_or(format!(
+                "column {} cell_of {} is not a table column",
+                tgt_cols[idx].name, cell_of
+            ))?;
+        tgt_cols[idx].src_idx = src_idx;
+    }
+    Ok(())
+}
+
+// hash of the cells read from the source, each cell is tagged with its type
+// so the number 1 and the text "1" differ, and separated so moving text
+// between adjacent cells changes the hash
+pub fn row_hash(tgt_cols: &[ColumnSpec], cells: &[Option<Cell>]) -> String {
+    let mut text = String::new();
+    for (tgt_col, cell) in tgt_cols.iter().zip(cells) {
+        if tgt_col.virtual_col.is_some() {
+            continue;
+        }
+        let cell = match cell {
+            None => "n".to_owned(),
+            Some(Cell::Bool(v)) => format!("b{}", v),
+            Some(Cell::I8(v)) => format!("i{}", v),
+            Some(Cell::I16(v)) => format!("i{}", v),
+            Some(Cell::I32(v)) => format!("i{}", v),
+            Some(Cell::I64(v)) => format!("i{}", v),
+            Some(Cell::F32(v)) => format!("f{}", v),
+            Some(Cell::F64(v)) => format!("f{}", v),
+            Some(Cell::Numeric(v)) => format!("f{}", v),
+            Some(Cell::String(v)) => format!("s{}:{}", v.len(), v),
+            Some(Cell::Date(v)) => format!("d{}", v),
+            Some(Cell::Timestamp(v)) => format!("t{}", v),
+            Some(Cell::Timestamptz(v)) => format!("z{}", v),
+            Some(Cell::Json(v)) => format!("j{}:{}", v.len(), v),
+        };
+        text.push_str(&cell);
+        text.push(';');
+    }
+    sha256_hex(text.as_bytes())
+}
+
+impl ColumnSpec {
+    pub fn new(
+        tgt_col: &Column,
+        server_opts: &impl OptionLookup,
+        table_opts: &impl OptionLookup,
+    ) -> Result<Self, FdwError> {
+        Self::with_options(
+            tgt_col.name(),
+            tgt_col.num(),
+            tgt_col.type_oid(),
+            server_opts,
+            table_opts,
+        )
+    }
+
+    // make a target column from its name, 1-based number and type, apart from
+    // the host column
+    pub fn with_options(
+        name: String,
+        num: u32,
+        type_oid: TypeOid,
+        server_opts: &impl OptionLookup,
+        table_opts: &impl OptionLookup,
+    ) -> Result<Self, FdwError> {
+        let get_opt = |key: &str| get_column_option(server_opts, table_opts, &name, key);
+
+        let null_values = get_opt("null_values")
+            .map(|v| parse_list_option(&v))
+            .unwrap_or_default();
+        let allowed_values = get_opt("allowed_values").map(|v| parse_list_option(&v));
+        let extract_regex = get_opt("extract_regex")
+            .map(|v| Regex::new(&v).map_err(|e| format!("invalid extract_regex '{}': {}", v, e)))
+            .transpose()?;
+
+        // an explicit decimal separator takes precedence over the locale one
+        let decimal_separator = match get_opt("decimal_separator").as_deref() {
+            Some(".") => '.',
+            Some(",") => ',',
+            Some(v) => {
+                return Err(format!(
+                    "invalid decimal_separator '{}', expect '.' or ','",
+                    v
+                ))
+            }
+            None => get_opt("locale")
+                .map(|locale| locale_decimal_separator(&locale))
+                .unwrap_or('.'),
+        };
+
+        let coercion = match get_opt("coercion").as_deref() {
+            None | Some("default") => Coercion::Default,
+            Some("strict") => Coercion::Strict,
+            Some("lenient") => Coercion::Lenient,
+            Some(v) => {
+                return Err(format!(
+                    "invalid coercion '{}', expect 'default', 'strict' or 'lenient'",
+                    v
+                ))
+            }
+        };
+
+        let non_finite = match get_opt("non_finite").as_deref() {
+            None | Some("null") => NonFinite::Null,
+            Some("nan") => NonFinite::Nan,
+            Some("error") => NonFinite::Error,
+            Some(v) => {
+                return Err(format!(
+                    "invalid non_finite '{}', expect 'null', 'nan' or 'error'",
+                    v
+                ))
+            }
+        };
+
+        let timezone = match get_opt("timezone") {
+            Some(tz) => Some(TimeZone::parse(&tz).ok_or_else(|| {
+                format!(
+                    "invalid timezone '{}', expect one of the {} built-in zone names \
+                     such as 'Europe/Paris', an offset such as '+02:00' or a POSIX TZ \
+                     rule, the zone names follow their current rules for all years",
+                    tz,
+                    ZONE_RULES.len()
+                )
+            })?),
+            None => None,
+        };
+
+        // only a column option, masking every column makes no sense
+        let mask = match table_opts.lookup(&format!("{}.mask", name)).as_deref() {
+            None => None,
+            Some("hash") => Some(Mask::Hash),
+            Some("partial") => Some(Mask::Partial),
+            Some("null") => Some(Mask::Null),
+            Some(v) => {
+                return Err(format!(
+                    "invalid mask '{}', expect 'hash', 'partial' or 'null'",
+                    v
+                ))
+            }
+        };
+        if matches!(mask, Some(Mask::Hash | Mask::Partial)) && !matches!(type_oid, TypeOid::String)
+        {
+            return Err(format!("column {} must have text type to be masked", name));
+        }
+        let mask_key = match (mask, get_opt("mask_key_id")) {
+            (Some(Mask::Hash), Some(key_id)) => Some(utils::get_vault_secret(&key_id).ok_or(
+                format!("cannot get mask key from Vault secret '{}'", key_id),
+            )?),
+            _ => None,
+        };
+
+        let newlines = match get_opt("newlines").as_deref() {
+            None | Some("keep") => Newlines::Keep,
+            Some("space") => Newlines::Space,
+            Some("escape") => Newlines::Escape,
+            Some("split") => Newlines::Split,
+            Some(v) => {
+                return Err(format!(
+                    "invalid newlines '{}', expect 'keep', 'space', 'escape' or 'split'",
+                    v
+                ))
+            }
+        };
+        if newlines == Newlines::Split && !matches!(type_oid, TypeOid::Json) {
+            return Err(format!(
+                "column {} must have jsonb type to split newlines",
+                name
+            ));
+        }
+
+        let null_write = match get_opt("null_on_write").as_deref() {
+            None | Some("clear") => NullWrite::Clear,
+            Some("keep") => NullWrite::Keep,
+            Some("sentinel") if null_values.is_empty() => {
+                return Err(format!(
+                    "column {} null_on_write 'sentinel' needs null_values",
+                    name
+                ));
+            }
+            Some("sentinel") => NullWrite::Sentinel,
+            Some(v) => {
+                return Err(format!(
+                    "invalid null_on_write '{}', expect 'clear', 'keep' or 'sentinel'",
+                    v
+                ))
+            }
+        };
+
+        // only a column option, other columns stay literal
+        let formula =
+            parse_bool_option("formula", table_opts.lookup(&format!("{}.formula", name)))?;
+        if formula && !matches!(type_oid, TypeOid::String) {
+            return Err(format!(
+                "column {} must have text type to write formulas",
+                name
+            ));
+        }
+
+        // a cell data column reads a property of the sheet cells of the column
+        // given by cell_of, by default the one named before its last
+        // underscore, e.g. amount for amount_note
+        let cell_data = table_opts
+            .lookup(&format!("{}.cell_data", name))
+            .map(|v| CellProperty::parse(&v))
+            .transpose()?;
+        let cell_of = match cell_data {
+            Some(_) => Some(
+                table_opts
+                    .lookup(&format!("{}.cell_of", name))
+                    .or_else(|| name.rsplit_once('_').map(|(base, _)| base.to_owned()))
+                    .ok_or(format!("column {} cell_data needs cell_of", name))?,
+            ),
+            None => None,
+        };
+
+        let virtual_col = cell_data
+            .map(VirtualColumn::CellData)
+            .or_else(|| VirtualColumn::parse(&name));
+        if let Some(virtual_col) = virtual_col {
+            if !virtual_col.accepts(type_oid) {
+                return Err(format!(
+                    "column {} must have {} type",
+                    name,
+                    type_name(virtual_col.type_oid())
+                ));
+            }
+        }
+
+        let mut spec = Self {
+            src_idx: num as usize - 1,
+            type_oid,
+            trim: parse_bool_option("trim", get_opt("trim"))?,
+            empty_as_null: parse_bool_option("empty_as_null", get_opt("empty_as_null"))?,
+            null_values,
+            allowed_values,
+            decimal_separator,
+            coercion,
+            non_finite,
+            date_format: get_opt("date_format"),
+            timezone,
+            virtual_col,
+            // only a column option, a table-wide expression makes no sense
+            expr: table_opts.lookup(&format!("{}.expr", name)),
+            extract_regex,
+            json_path: table_opts
+                .lookup(&format!("{}.json_path", name))
+                .unwrap_or_else(|| format!("/{}", name)),
+            mask,
+            mask_key,
+            formula,
+            null_write,
+            default: None,
+            newlines,
+            cell_of,
+            name,
+        };
+        // only a column option, typed like the column
+        if let Some(text) = table_opts.lookup(&format!("{}.default", spec.name)) {
+            spec.default = Some(spec.parse_default(&text)?);
+        }
+        Ok(spec)
+    }
+
+    // parse the text of the default option to a cell of the column type,
+    // numbers follow the decimal separator and dates the date format
+    fn parse_default(&self, text: &str) -> Result<Cell, FdwError> {
+        let invalid = || {
+            format!(
+                "column {} default '{}' is not a valid {} value",
+                self.name,
+                text,
+                type_name(self.type_oid)
+            )
+        };
+        let integer = || parse_formatted_integer(text, self.decimal_separator).ok_or_else(invalid);
+        let float = || parse_formatted_number(text, self.decimal_separator).ok_or_else(invalid);
+        let datetime = || {
+            match &self.date_format {
+                Some(fmt) => parse_datetime(text, fmt),
+                None => ["%Y-%m-%dT%H:%M:%S%.f", "%F %T%.f", "%F"]
+                    .iter()
+                    .find_map(|fmt| parse_datetime(text, fmt)),
+            }
+            .and_then(|parts| parts.epoch_micros())
+            .ok_or_else(invalid)
+        };
+
+        Ok(match self.type_oid {
+            TypeOid::Bool => Cell::Bool(parse_bool_text(text).ok_or_else(invalid)?),
+            TypeOid::I8 => Cell::I8(integer()?.try_into().map_err(|_| invalid())?),
+            TypeOid::I16 => Cell::I16(integer()?.try_into().map_err(|_| invalid())?),
+            TypeOid::I32 => Cell::I32(integer()?.try_into().map_err(|_| invalid())?),
+            TypeOid::I64 => Cell::I64(integer()?),
+            TypeOid::F32 => Cell::F32(float()? as _),
+            TypeOid::F64 => Cell::F64(float()?),
+            TypeOid::Numeric => Cell::Numeric(float()?),
+            TypeOid::Date => Cell::Date(datetime()?.div_euclid(MICROS_PER_SEC)),
+            TypeOid::Timestamp => Cell::Timestamp(datetime()?),
+            TypeOid::Timestamptz => {
+                let v = datetime()?;
+                Cell::Timestamptz(self.timezone.as_ref().map_or(v, |tz| tz.to_utc(v)))
+            }
+            TypeOid::String => Cell::String(text.to_owned()),
+            _ => return Err(format!("column {} data type is not supported", self.name)),
+        })
+    }
+
+    // convert a source cell to target cell, the source value is moved out
+    pub fn convert(&self, src: &mut GvizCell) -> Result<Option<Cell>, FdwError> {
+        if self.mask == Some(Mask::Null) {
+            return Ok(None);
+        }
+
+        // empty cells get the default, not cells failed to convert
+        if self.default.is_some() && self.is_empty(src) {
+            return Ok(self.default.clone());
+        }
+        self.convert_value(src)
+    }
+
+    // text of a cell value for errors and warnings, masked like the cell so
+    // masked values never show up in them
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.mask {
+            Some(mask) => Cow::Owned(
+                mask.apply(text, self.mask_key.as_deref().map(str::as_bytes))
+                    .unwrap_or_else(|| "<masked>".to_owned()),
+            ),
+            None => Cow::Borrowed(text),
+        }
+    }
+
+    // replace a string cell with the part matched by extract_regex, its first
+    // capture group if it has one, false if it doesn't match so the cell is
+    // NULL
+    fn extract(&self, src: &mut GvizCell) -> Result<bool, FdwError> {
+        let (Some(re), JsonValue::String(v)) = (&self.extract_regex, &src.v) else {
+            return Ok(true);
+        };
+        match re.captures(v).and_then(|c| c.get(1).or_else(|| c.get(0))) {
+            Some(m) => src.v = JsonValue::String(m.as_str().to_owned()),
+            None if self.coercion == Coercion::Strict => {
+                return Err(format!(
+                    "column {} value '{}' does not match extract_regex",
+                    self.name,
+                    self.redact(v)
+                ));
+            }
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn is_placeholder(&self, src: &GvizCell) -> bool {
+        matches!(&src.v, JsonValue::String(v) if self.is_null_value(v))
+    }
+
+    // text of a cell compared with allowed_values
+    fn allowed_text(&self, src: &GvizCell) -> String {
+        let text = cell_text(src);
+        match self.trim {
+            true => text.trim_matches(is_space).to_owned(),
+            false => text.into_owned(),
+        }
+    }
+
+    // check if a cell value is one of allowed_values, NULL is always allowed
+    fn is_allowed(&self, src: &GvizCell) -> bool {
+        let Some(allowed_values) = &self.allowed_values else {
+            return true;
+        };
+        let text = self.allowed_text(src);
+        let is_null = src.v.is_null() || (self.empty_as_null && text.is_empty());
+        is_null || allowed_values.contains(&text)
+    }
+
+    // check if a source cell has no value or only an empty string
+    pub fn is_empty(&self, src: &GvizCell) -> bool {
+        match &src.v {
+            JsonValue::Null => src.f.as_deref().is_none_or(str::is_empty),
+            JsonValue::String(v) if self.trim => v.trim_matches(is_space).is_empty(),
+            JsonValue::String(v) => v.is_empty(),
+            _ => false,
+        }
+    }
+
+    // convert the value of a source cell regardless of the default
+    fn convert_value(&self, src: &mut GvizCell) -> Result<Option<Cell>, FdwError> {
+        if !self.extract(src)? {
+            return Ok(None);
+        }
+
+        // placeholder strings are NULL, whatever the target column type
+        if self.is_placeholder(src) {
+            return Ok(None);
+        }
+
+        // values outside of allowed_values are an error in strict coercion,
+        // otherwise they are NULL, summarized like coerced cells
+        if !self.is_allowed(src) {
+            if self.coercion == Coercion::Strict {
+                return Err(format!(
+                    "column {} value '{}' is not an allowed value",
+                    self.name,
+                    self.redact(&self.allowed_text(src))
+                ));
+            }
+            return Ok(None);
+        }
+
+        // in strict coercion, a value not having the target type is an error
+        // rather than NULL, non-finite values are left to the non_finite option
+        if self.coercion == Coercion::Strict
+            && !self.accepts(src)
+            && !(is_float_type(self.type_oid) && non_finite_value(src).is_some())
+        {
+            return Err(format!(
+                "column {} expects {} value, got '{}'",
+                self.name,
+                type_name(self.type_oid),
+                self.redact(&cell_text(src))
+            ));
+        }
+
+        // every cell type of the host is converted here
+        let cell = match self.type_oid {
+            TypeOid::Bool => self.boolean(src).map(Cell::Bool),
+            TypeOid::I8 => self.integer(src)?.map(Cell::I8),
+            TypeOid::I16 => self.integer(src)?.map(Cell::I16),
+            TypeOid::I32 => self.integer(src)?.map(Cell::I32),
+            TypeOid::I64 => self.integer(src)?.map(Cell::I64),
+            TypeOid::F32 => self.float(src)?.map(|v| Cell::F32(v as _)),
+            TypeOid::F64 => self.float(src)?.map(Cell::F64),
+            TypeOid::Numeric => self.float(src)?.map(Cell::Numeric),
+            TypeOid::Date => self
+                .datetime(src)
+                .map(|v| Cell::Date(v.div_euclid(MICROS_PER_SEC))),
+            TypeOid::Timestamp => self.datetime(src).map(Cell::Timestamp),
+            TypeOid::Timestamptz => self
+                .datetime(src)
+                .map(|v| Cell::Timestamptz(self.timezone.as_ref().map_or(v, |tz| tz.to_utc(v)))),
+            TypeOid::String => match self.mask {
+                Some(mask) => self
+                    .string(src)
+                    .and_then(|v| mask.apply(&v, self.mask_key.as_deref().map(str::as_bytes))),
+                None => self.string(src),
+            }
+            .map(Cell::String),
+            TypeOid::Json if self.newlines == Newlines::Split => self
+                .string(src)
+                .map(|v| Cell::Json(JsonValue::from(split_lines(&v)).to_string())),
+            // JSON documents, e.g. of a key/value sheet, are passed as is and
+            // scalar cells become JSON scalars, e.g. a text cell a JSON string
+            TypeOid::Json => match &src.v {
+                JsonValue::Null => None,
+                v => Some(Cell::Json(v.to_string())),
+            },
+        };
+
+        Ok(cell)
+    }
+
+    // get the numeric value of a source cell, numbers without raw value are
+    // parsed from the formatted value, which follows the sheet locale, and
+    // text cells like "1,234,567" or "1.2E6" are parsed in lenient coercion
+    pub fn number(&self, src: &GvizCell) -> Option<f64> {
+        match &src.v {
+            JsonValue::Number(v) => v.as_f64(),
+            JsonValue::Null => src
+                .f
+                .as_deref()
+                .and_then(|f| parse_formatted_number(f, self.decimal_separator)),
+            JsonValue::String(v) if self.coercion == Coercion::Lenient => {
+                parse_formatted_number(v, self.decimal_separator)
+            }
+            _ => None,
+        }
+    }
+
+    // get the value of a source cell for a float column, applying the
+    // non_finite policy to NaN, infinities and sheet error cells
+    pub fn float(&self, src: &GvizCell) -> Result<Option<f64>, FdwError> {
+        match non_finite_value(src).or_else(|| self.number(src)) {
+            Some(v) if !v.is_finite() => match self.non_finite {
+                NonFinite::Null => Ok(None),
+                NonFinite::Nan => Ok(Some(v)),
+                NonFinite::Error => Err(format!(
+                    "column {} has non-finite value {}",
+                    self.name,
+                    src.f
+                        .as_deref()
+                        .map_or_else(|| src.v.to_string(), str::to_owned)
+                )),
+            },
+            v => Ok(v),
+        }
+    }
+
+    // get the integer value of a source cell in the range of the target type
+    pub fn integer<T: TryFrom<i64>>(&self, src: &GvizCell) -> Result<Option<T>, FdwError> {
+        match self.integer_value(src)? {
+            Some(v) => T::try_from(v)
+                .map(Some)
+                .map_err(|_| format!("column {} value {} is out of range", self.name, v)),
+            None => Ok(None),
+        }
+    }
+
+    // get the integer value of a source cell, sheet numbers are doubles so
+    // integers beyond 2^53 are exact only in text cells, from their digits,
+    // numbers beyond it are an error rather than silently losing precision,
+    // and so are numbers with a fraction in strict coercion, which are NULL
+    // otherwise
+    pub fn integer_value(&self, src: &GvizCell) -> Result<Option<i64>, FdwError> {
+        // exact integer if the string has one, otherwise its double value
+        let parse_str = |s: &str| match parse_formatted_integer(s, self.decimal_separator) {
+            Some(v) => Some(Ok(v)),
+            None => parse_formatted_number(s, self.decimal_separator).map(Err),
+        };
+        let v = match &src.v {
+            JsonValue::Number(v) => v.as_i64().map(Ok).or_else(|| v.as_f64().map(Err)),
+            JsonValue::Null => src.f.as_deref().and_then(parse_str),
+            JsonValue::String(v) if self.coercion == Coercion::Lenient => parse_str(v),
+            _ => None,
+        };
+
+        match v {
+            Some(Ok(v)) => Ok(Some(v)),
+            Some(Err(v)) => match f64_to_i64(v) {
+                Some(v) => Ok(Some(v)),
+                // the formatted text of a number beyond 2^53 is rounded too
+                None if !has_fraction(v) => Err(format!(
+                    "column {} value {} cannot be represented exactly as an integer",
+                    self.name, v
+                )),
+                None if self.coercion == Coercion::Strict => Err(format!(
+                    "column {} value {} is not an integer",
+                    self.name, v
+                )),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // get the boolean value of a source cell, text cells such as "TRUE",
+    // "yes" or "0" and numbers 1 and 0 are accepted in lenient coercion
+    pub fn boolean(&self, src: &GvizCell) -> Option<bool> {
+        match &src.v {
+            JsonValue::Bool(v) => Some(*v),
+            JsonValue::String(v) if self.coercion == Coercion::Lenient => parse_bool_text(v),
+            JsonValue::Number(v) if self.coercion == Coercion::Lenient => match v.as_f64() {
+                Some(1.0) => Some(true),
+                Some(0.0) => Some(false),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // get the microseconds since Unix epoch of a source cell, from gviz date
+    // values like "Date(2024,0,31)" or from text cells parsed by date_format,
+    // ISO 8601 text is also accepted in lenient coercion
+    pub fn datetime(&self, src: &GvizCell) -> Option<i64> {
+        let parse_text = |s: &str| match &self.date_format {
+            Some(fmt) => parse_datetime(s, fmt),
+            None if self.coercion == Coercion::Lenient => {
+                ["%Y-%m-%dT%H:%M:%S%.f", "%F %T%.f", "%F"]
+                    .iter()
+                    .find_map(|fmt| parse_datetime(s, fmt))
+            }
+            None => None,
+        };
+        let parts = match (&src.v, &src.f) {
+            (JsonValue::String(v), _) if v.starts_with("Date(") => parse_gviz_date(v),
+            (JsonValue::String(v), _) => parse_text(v),
+            (JsonValue::Null, Some(f)) => parse_text(f),
+            _ => None,
+        };
+        parts.and_then(|parts| parts.epoch_micros())
+    }
+
+    // get the string value of a source cell, number and boolean cells are
+    // converted to their displayed text in lenient coercion
+    pub fn string(&self, src: &mut GvizCell) -> Option<String> {
+        match src.v.take() {
+            JsonValue::String(v) => self.clean_string(v),
+            JsonValue::Null => None,
+            v if self.coercion == Coercion::Lenient => {
+                self.clean_string(src.f.take().unwrap_or_else(|| v.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    // check if the conversion turns a source cell into NULL because it is not
+    // an allowed value or, in lenient coercion, cannot be converted, blank
+    // cells are NULL anyway and non-finite values are left to the non_finite
+    // option
+    pub fn coerces_to_null(&self, src: &GvizCell) -> bool {
+        // masked as NULL, the cells are not converted at all
+        if self.mask == Some(Mask::Null) {
+            return false;
+        }
+        // values outside of allowed_values are NULL unless coercion is strict,
+        // checked after extract_regex like the conversion does
+        if self.allowed_values.is_some() && self.coercion != Coercion::Strict {
+            let mut src = src.clone();
+            if self.extract(&mut src).unwrap_or(false)
+                && !self.is_placeholder(&src)
+                && !self.is_allowed(&src)
+            {
+                return true;
+            }
+        }
+        if self.coercion != Coercion::Lenient {
+            return false;
+        }
+        // numbers with a fraction are not integers
+        let integer = is_numeric_type(self.type_oid) && !is_float_type(self.type_oid);
+        if integer && self.number(src).is_some_and(has_fraction) {
+            return true;
+        }
+        if self.accepts(src) {
+            return false;
+        }
+        let blank = cell_text(src).trim_matches(is_space).is_empty();
+        let non_finite = is_float_type(self.type_oid) && non_finite_value(src).is_some();
+        !blank && !non_finite
+    }
+
+    // check if a source cell value has the target column type, an empty cell
+    // fits any type
+    pub fn accepts(&self, src: &GvizCell) -> bool {
+        let numeric = is_numeric_type(self.type_oid);
+        match &src.v {
+            JsonValue::Null if numeric => src.f.is_none() || self.number(src).is_some(),
+            JsonValue::Null if self.date_format.is_some() && is_datetime_type(self.type_oid) => {
+                src.f.is_none() || self.datetime(src).is_some()
+            }
+            JsonValue::Null => true,
+            JsonValue::Number(_) => numeric || matches!(self.type_oid, TypeOid::Json),
+            JsonValue::Bool(_) => matches!(self.type_oid, TypeOid::Bool | TypeOid::Json),
+            JsonValue::String(_) if is_datetime_type(self.type_oid) => self.datetime(src).is_some(),
+            JsonValue::String(_) => matches!(self.type_oid, TypeOid::String | TypeOid::Json),
+            JsonValue::Array(_) | JsonValue::Object(_) => matches!(self.type_oid, TypeOid::Json),
+        }
+    }
+
+    // check the type gviz declares for the source column against the target
+    // column type, a message if the cells of the column cannot convert, e.g.
+    // a "string" column read into an integer column without lenient coercion
+    pub fn declared_type_mismatch(&self, declared: &str, letters: &str) -> Option<String> {
+        let lenient = self.coercion == Coercion::Lenient;
+        let fits = match declared {
+            _ if matches!(self.type_oid, TypeOid::String) => true,
+            "number" => {
+                is_numeric_type(self.type_oid)
+                    || matches!(self.type_oid, TypeOid::Json)
+                    || (lenient && matches!(self.type_oid, TypeOid::Bool))
+            }
+            "boolean" => matches!(self.type_oid, TypeOid::Bool | TypeOid::Json),
+            "date" | "datetime" => is_datetime_type(self.type_oid),
+            "string" => lenient || matches!(self.type_oid, TypeOid::Json),
+            "timeofday" => false,
+            // columns without a declared type
+            _ => true,
+        };
+        (!fits).then(|| {
+            format!(
+                "column {} is {} but sheet column {} has type {}",
+                self.name,
+                type_name(self.type_oid),
+                letters,
+                declared
+            )
+        })
+    }
+
+    // check if a source string value is one of the NULL placeholders
+    pub fn is_null_value(&self, s: &str) -> bool {
+        let s = if self.trim {
+            s.trim_matches(is_space)
+        } else {
+            s
+        };
+        self.null_values.iter().any(|v| v == s)
+    }
+
+    // clean up a source string value, NULL if nothing is left of it
+    pub fn clean_string(&self, s: String) -> Option<String> {
+        let s = if self.trim { trim_cell(s) } else { s };
+        let s = self.newlines.normalize(s);
+        if self.empty_as_null && s.is_empty() {
+            return None;
+        }
+        Some(s)
+    }
+}
+
+// check if a char is whitespace, including the zero width and non-breaking
+// spaces often found in sheets
+pub fn is_space(c: char) -> bool {
+    c.is_whitespace() || c == '\u{200b}' || c == '\u{feff}'
+}
+
+// strip leading and trailing whitespace from a string cell
+pub fn trim_cell(s: String) -> String {
+    let trimmed = s.trim_matches(is_space);
+    if trimmed.len() == s.len() {
+        s
+    } else {
+        trimmed.to_owned()
+    }
+}
+
+// parse boolean-ish text, case-insensitive
+pub fn parse_bool_text(s: &str) -> Option<bool> {
+    let s = s.trim_matches(is_space);
+    if ["true", "yes", "1"]
+        .iter()
+        .any(|v| s.eq_ignore_ascii_case(v))
+    {
+        Some(true)
+    } else if ["false", "no", "0"]
+        .iter()
+        .any(|v| s.eq_ignore_ascii_case(v))
+    {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// version of a sheet row, the SHA-256 of the displayed texts of its cells at
+// the positions of version_positions, prefixed by the row so the version is
+// also its rowid, e.g. "12:9f86d0..."
+pub fn row_version(sheet_row: usize, texts: &[&str]) -> String {
+    let mut text = String::new();
+    for cell in texts {
+        text.push_str(&format!("{}:{};", cell.len(), cell));
+    }
+    format!("{}:{}", sheet_row, sha256_hex(text.as_bytes()))
+}
+
+// positions in the range of the sheet cells a row version covers, the cells
+// of the columns read from the sheet
+pub fn version_positions(tgt_cols: &[ColumnSpec]) -> Vec<usize> {
+    let mut positions: Vec<usize> = tgt_cols
+        .iter()
+        .filter(|c| c.virtual_col.is_none() && c.expr.is_none())
+        .map(|c| c.src_idx)
+        .collect();
+    positions.sort_unstable();
+    positions.dedup();
+    positions
+}
+
+// text of a source cell as displayed, the formatted value if there is one,
+// as the Sheets API returns it with valueRenderOption FORMATTED_VALUE
+pub fn display_text(src: &GvizCell) -> Cow<'_, str> {
+    match (&src.f, &src.v) {
+        (Some(f), _) => Cow::Borrowed(f),
+        (None, JsonValue::Null) => Cow::Borrowed(""),
+        (None, JsonValue::String(v)) => Cow::Borrowed(v),
+        (None, v) => Cow::Owned(v.to_string()),
+    }
+}
+
+// text of a source cell as shown in the sheet, used for matching and messages
+pub fn cell_text(src: &GvizCell) -> Cow<'_, str> {
+    match (&src.v, &src.f) {
+        (JsonValue::String(v), _) => Cow::Borrowed(v),
+        (_, Some(f)) => Cow::Borrowed(f),
+        (v, None) => Cow::Owned(v.to_string()),
+    }
+}
+
+// check if a target column type is one of the numeric types
+pub fn is_numeric_type(type_oid: TypeOid) -> bool {
+    matches!(
+        type_oid,
+        TypeOid::I8
+            | TypeOid::I16
+            | TypeOid::I32
+            | TypeOid::I64
+            | TypeOid::F32
+            | TypeOid::F64
+            | TypeOid::Numeric
+    )
+}
+
+// check if a target column type holds floating point values
+pub fn is_float_type(type_oid: TypeOid) -> bool {
+    matches!(type_oid, TypeOid::F32 | TypeOid::F64 | TypeOid::Numeric)
+}
+
+// get the non-finite value represented by a source cell, sheet error cells
+// such as "#DIV/0!" or "#N/A" are NaN
+pub fn non_finite_value(src: &GvizCell) -> Option<f64> {
+    let s = match (&src.v, &src.f) {
+        (JsonValue::String(v), _) => v.as_str(),
+        (JsonValue::Null, Some(f)) => f.as_str(),
+        _ => return None,
+    }
+    .trim_matches(is_space);
+    if s.starts_with('#') && (s.ends_with('!') || s.ends_with('?') || s == "#N/A") {
+        return Some(f64::NAN);
+    }
+    match s.to_ascii_lowercase().as_str() {
+        "nan" => Some(f64::NAN),
+        "inf" | "infinity" | "+inf" | "+infinity" | "∞" => Some(f64::INFINITY),
+        "-inf" | "-infinity" | "-∞" => Some(f64::NEG_INFINITY),
+        _ => None,
+    }
+}
+
+// check if a target column type holds a date or time
+pub fn is_datetime_type(type_oid: TypeOid) -> bool {
+    matches!(
+        type_oid,
+        TypeOid::Date | TypeOid::Timestamp | TypeOid::Timestamptz
+    )
+}
+
+// name of a target column type used in conversion error messages
+pub fn type_name(type_oid: TypeOid) -> &'static str {
+    match type_oid {
+        TypeOid::Bool => "boolean",
+        TypeOid::String => "string",
+        TypeOid::Date => "date",
+        TypeOid::Timestamp | TypeOid::Timestamptz => "timestamp",
+        TypeOid::Json => "json",
+        _ => "number",
+    }
+}
+
+// decimal separator of a locale such as 'de_DE' or 'pt-BR', languages not
+// listed here use a dot
+pub fn locale_decimal_separator(locale: &str) -> char {
+    let mut parts = locale.split(['_', '-']);
+    let lang = parts.next().unwrap_or_default().to_lowercase();
+    let region = parts.next().unwrap_or_default().to_uppercase();
+    match (lang.as_str(), region.as_str()) {
+        ("de" | "it" | "fr", "CH" | "LI") => '.',
+        ("es", "MX" | "US" | "PR" | "DO" | "GT" | "HN" | "NI" | "PA" | "SV") => '.',
+        (
+            "az" | "be" | "bg" | "ca" | "cs" | "da" | "de" | "el" | "es" | "et" | "eu" | "fi"
+            | "fr" | "gl" | "hr" | "hu" | "id" | "is" | "it" | "ka" | "kk" | "lt" | "lv" | "mk"
+            | "nb" | "nl" | "nn" | "no" | "pl" | "pt" | "ro" | "ru" | "sk" | "sl" | "sq" | "sr"
+            | "sv" | "tr" | "uk" | "vi",
+            _,
+        ) => ',',
+        _ => '.',
+    }
+}
+
+// convert a double to integer, None if it has a fraction or is beyond the
+// range in which doubles represent integers exactly
+pub fn f64_to_i64(v: f64) -> Option<i64> {
+    const MAX_EXACT: f64 = (1u64 << f64::MANTISSA_DIGITS) as f64;
+    (v.abs() <= MAX_EXACT && !has_fraction(v)).then_some(v as i64)
+}
+
+// check if a double is a finite number with a fraction
+fn has_fraction(v: f64) -> bool {
+    v.is_finite() && v.fract() != 0.0
+}
+
+// parse a formatted integer such as "123,456,789,012,345,678" without going
+// through a double
+pub fn parse_formatted_integer(s: &str, decimal_separator: char) -> Option<i64> {
+    let group_separator = if decimal_separator == ',' { '.' } else { ',' };
+    let s: String = s
+        .chars()
+        .filter(|c| *c != group_separator && *c != '\'' && !is_space(*c))
+        .collect();
+    s.parse().ok()
+}
+
+// parse a formatted number such as "1.234,56", "1 234.56" or "1.2E6", digit
+// group separators are dropped and the decimal separator is replaced by a dot
+pub fn parse_formatted_number(s: &str, decimal_separator: char) -> Option<f64> {
+    let group_separator = if decimal_separator == ',' { '.' } else { ',' };
+    let s: String = s
+        .chars()
+        .filter(|c| *c != group_separator && *c != '\'' && !is_space(*c))
+        .map(|c| if c == decimal_separator { '.' } else { c })
+        .collect();
+    s.parse().ok()
+}
@@ -0,0 +1,156 @@
+// CSV file over HTTP, every field is a text cell converted like the text
+// cells of a sheet
+use serde_json::Value as JsonValue;
+
+use crate::bindings::supabase::wrappers::{
+    http,
+    types::{FdwError, Options},
+};
+use crate::column::ColumnSpec;
+use crate::gviz::{GvizCell, GvizRow};
+use crate::options::{get_bool_option, get_char_option};
+use crate::source::Source;
+
+// CSV dialect of a source, set by table options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvFormat {
+    pub delimiter: char,
+    // quote char, or None if fields are never quoted
+    pub quote: Option<char>,
+    // the first record is a header and not a row
+    pub header: bool,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            quote: Some('"'),
+            header: false,
+        }
+    }
+}
+
+// CSV source, CSV fields are all text so they are parsed into column types
+// leniently unless the coercion option says otherwise
+#[derive(Debug, Default)]
+pub struct CsvSource {
+    format: CsvFormat,
+}
+
+impl CsvSource {
+    pub fn new(opts: &Options) -> Result<Self, FdwError> {
+        let format = CsvFormat {
+            delimiter: get_char_option(opts, "delimiter")?.unwrap_or(','),
+            quote: match opts.get("quote").as_deref() {
+                // an empty quote option turns quoting off
+                Some("") => None,
+                _ => Some(get_char_option(opts, "quote")?.unwrap_or('"')),
+            },
+            header: get_bool_option(opts, "header")?,
+        };
+        Ok(Self { format })
+    }
+}
+
+impl Source for CsvSource {
+    fn column_defaults(&self) -> &'static [(&'static str, &'static str)] {
+        &[("coercion", "lenient")]
+    }
+
+    fn build_request(&mut self, url: &str, _fetched: usize) -> http::Request {
+        let headers: Vec<(String, String)> = vec![
+            ("user-agent".to_owned(), "Sheets FDW".to_owned()),
+            ("accept".to_owned(), "text/csv".to_owned()),
+        ];
+
+        http::Request {
+            method: http::Method::Get,
+            url: url.to_owned(),
+            headers,
+            body: String::default(),
+        }
+    }
+
+    fn parse_rows(
+        &mut self,
+        resp: &http::Response,
+        _tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        http::error_for_status(resp)?;
+        parse_csv_rows(&resp.body, &self.format)
+    }
+}
+
+// parse CSV text into source rows, quoted fields may contain delimiters, line
+// breaks and doubled quote chars
+pub fn parse_csv_rows(body: &str, format: &CsvFormat) -> Result<Vec<GvizRow>, FdwError> {
+    let mut rows = Vec::new();
+    let mut record: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut chars = body
+        .strip_prefix('\u{feff}')
+        .unwrap_or(body)
+        .chars()
+        .peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if Some(c) == format.quote {
+                // a doubled quote char is a literal one inside quotes
+                if chars.peek().copied() == format.quote {
+                    field.push(c);
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        } else if Some(c) == format.quote && field.is_empty() {
+            in_quotes = true;
+        } else if c == format.delimiter {
+            record.push(std::mem::take(&mut field));
+        } else if c == '\n' || c == '\r' {
+            if c == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            record.push(std::mem::take(&mut field));
+            rows.push(std::mem::take(&mut record));
+            line += 1;
+        } else {
+            field.push(c);
+        }
+    }
+    if in_quotes {
+        return Err(format!("unterminated quoted field at line {}", line));
+    }
+    // the last line may have no line break
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        rows.push(record);
+    }
+
+    let skip = if format.header { 1 } else { 0 };
+    Ok(rows
+        .into_iter()
+        .skip(skip)
+        .map(|record| GvizRow {
+            c: record
+                .into_iter()
+                .map(|v| {
+                    Some(GvizCell {
+                        v: JsonValue::String(v),
+                        f: None,
+                    })
+                })
+                .collect(),
+            ..Default::default()
+        })
+        .collect())
+}
@@ -0,0 +1,559 @@
+// date, time and time zone handling, the guest has no date library
+use crate::column::is_space;
+
+pub const MICROS_PER_SEC: i64 = 1_000_000;
+
+// civil date and time read from a sheet cell, without time zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTimeParts {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub micro: u32,
+}
+
+impl Default for DateTimeParts {
+    fn default() -> Self {
+        Self {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+            micro: 0,
+        }
+    }
+}
+
+impl DateTimeParts {
+    // gviz date value, e.g. "Date(2024,0,31,12,30,0,0)" with 0-based month
+    pub fn to_gviz_date(self) -> String {
+        format!(
+            "Date({},{},{},{},{},{},{})",
+            self.year,
+            self.month - 1,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.micro / 1000
+        )
+    }
+
+    // civil date and time of microseconds since Unix epoch
+    pub fn from_epoch_micros(micros: i64) -> Self {
+        let secs = micros.div_euclid(MICROS_PER_SEC);
+        let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+        let secs = secs.rem_euclid(86_400);
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs / 3_600) as u32,
+            minute: (secs / 60 % 60) as u32,
+            second: (secs % 60) as u32,
+            micro: micros.rem_euclid(MICROS_PER_SEC) as u32,
+        }
+    }
+
+    // date as text the sheet parses, e.g. "2024-01-31"
+    pub fn date_text(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    // date and time as text the sheet parses, e.g. "2024-01-31 12:30:00",
+    // with the fraction of the second if there is one
+    pub fn datetime_text(&self) -> String {
+        let text = format!(
+            "{} {:02}:{:02}:{:02}",
+            self.date_text(),
+            self.hour,
+            self.minute,
+            self.second
+        );
+        match self.micro {
+            0 => text,
+            micro => format!("{}.{:06}", text, micro),
+        }
+    }
+
+    // microseconds since Unix epoch, None if any part is out of range
+    pub fn epoch_micros(&self) -> Option<i64> {
+        if !(1..=12).contains(&self.month)
+            || self.day < 1
+            || self.day > days_in_month(self.year, self.month)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 59
+            || self.micro > 999_999
+        {
+            return None;
+        }
+        let days = days_from_civil(self.year, self.month, self.day);
+        let secs =
+            days * 86_400 + self.hour as i64 * 3_600 + self.minute as i64 * 60 + self.second as i64;
+        Some(secs * MICROS_PER_SEC + self.micro as i64)
+    }
+}
+
+// sheet serial number of microseconds since Unix epoch, days since 1899-12-30
+// with the time of day as the fraction
+pub fn serial_from_epoch_micros(micros: i64) -> f64 {
+    const EPOCH_SERIAL: f64 = 25_569.0;
+    EPOCH_SERIAL + micros as f64 / (86_400.0 * MICROS_PER_SEC as f64)
+}
+
+pub fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// days since Unix epoch of a proleptic Gregorian date
+// ref: http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// civil year, month and day of a number of days since Unix epoch
+// ref: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+// civil year of a number of days since Unix epoch
+pub fn year_from_days(days: i64) -> i64 {
+    civil_from_days(days).0
+}
+
+// POSIX TZ rules of commonly used zones, the guest has no time zone database
+// so zones not listed here need an offset or a POSIX TZ rule instead, and the
+// current rule of a zone is used for all years, so datetimes from before a
+// zone changed its offset or daylight saving dates, e.g. Europe/Moscow before
+// 2014, are converted with the wrong offset
+pub const ZONE_RULES: &[(&str, &str)] = &[
+    ("Africa/Cairo", "EET-2EEST,M4.5.5/0,M10.5.4/24"),
+    ("Africa/Johannesburg", "SAST-2"),
+    ("Africa/Lagos", "WAT-1"),
+    ("Africa/Nairobi", "EAT-3"),
+    ("America/Anchorage", "AKST9AKDT,M3.2.0,M11.1.0"),
+    ("America/Argentina/Buenos_Aires", "<-03>3"),
+    ("America/Bogota", "<-05>5"),
+    ("America/Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Lima", "<-05>5"),
+    ("America/Los_Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    ("America/Mexico_City", "CST6"),
+    ("America/New_York", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Phoenix", "MST7"),
+    ("America/Santiago", "<-04>4<-03>,M9.1.6/24,M4.1.6/24"),
+    ("America/Sao_Paulo", "<-03>3"),
+    ("America/Toronto", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Vancouver", "PST8PDT,M3.2.0,M11.1.0"),
+    ("Asia/Bangkok", "<+07>-7"),
+    ("Asia/Dubai", "<+04>-4"),
+    ("Asia/Hong_Kong", "HKT-8"),
+    ("Asia/Jakarta", "WIB-7"),
+    ("Asia/Kolkata", "IST-5:30"),
+    ("Asia/Manila", "PST-8"),
+    ("Asia/Seoul", "KST-9"),
+    ("Asia/Shanghai", "CST-8"),
+    ("Asia/Singapore", "<+08>-8"),
+    ("Asia/Taipei", "CST-8"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Australia/Adelaide", "ACST-9:30ACDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Brisbane", "AEST-10"),
+    ("Australia/Melbourne", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Perth", "AWST-8"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Europe/Amsterdam", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Athens", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Brussels", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Bucharest", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Budapest", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Copenhagen", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Dublin", "GMT0IST,M3.5.0/1,M10.5.0"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Istanbul", "<+03>-3"),
+    ("Europe/Kyiv", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Lisbon", "WET0WEST,M3.5.0/1,M10.5.0"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("Europe/Madrid", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Moscow", "MSK-3"),
+    ("Europe/Oslo", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Paris", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Prague", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Rome", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Stockholm", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Vienna", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Warsaw", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Zurich", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Pacific/Auckland", "NZST-12NZDT,M9.5.0,M4.1.0/3"),
+    ("Pacific/Honolulu", "HST10"),
+];
+
+// day and time of a daylight saving transition, "Mm.w.d/time" in POSIX TZ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransitionRule {
+    pub month: u32,
+    // week of the month 1 to 5, 5 is the last week
+    pub week: u32,
+    // day of the week, 0 is Sunday
+    pub weekday: u32,
+    // local time of day in seconds, may be negative or beyond 24 hours
+    pub time: i64,
+}
+
+impl TransitionRule {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (date, time) = match s.split_once('/') {
+            Some((date, time)) => (date, parse_tz_offset(time)?),
+            None => (s, 7_200),
+        };
+        let mut parts = date.strip_prefix('M')?.split('.');
+        let mut next = || parts.next()?.parse::<u32>().ok();
+        let rule = Self {
+            month: next()?,
+            week: next()?,
+            weekday: next()?,
+            time,
+        };
+        ((1..=12).contains(&rule.month) && (1..=5).contains(&rule.week) && rule.weekday <= 6)
+            .then_some(rule)
+    }
+
+    // local seconds since Unix epoch of the transition in a year
+    pub fn local_secs(&self, year: i64) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        let first_weekday = (first + 4).rem_euclid(7) as u32;
+        let mut day = 1 + (self.weekday + 7 - first_weekday) % 7 + (self.week - 1) * 7;
+        while day > days_in_month(year, self.month) {
+            day -= 7;
+        }
+        (first + day as i64 - 1) * 86_400 + self.time
+    }
+}
+
+// time zone applied to naive sheet datetimes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    // offset from UTC in seconds during standard time
+    pub std_offset: i64,
+    // offset during daylight saving time and when it starts and ends
+    pub dst: Option<(i64, TransitionRule, TransitionRule)>,
+}
+
+impl TimeZone {
+    // parse a zone name, a fixed offset such as '+02:00' or a POSIX TZ rule
+    // such as 'CET-1CEST,M3.5.0,M10.5.0/3'
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if let Some((_, rule)) = ZONE_RULES.iter().find(|(name, _)| *name == s) {
+            return Self::parse_posix(rule);
+        }
+        match s {
+            "UTC" | "Etc/UTC" | "GMT" | "Z" => Some(Self {
+                std_offset: 0,
+                dst: None,
+            }),
+            _ if s.starts_with(['+', '-']) => Some(Self {
+                std_offset: parse_tz_offset(s)?,
+                dst: None,
+            }),
+            _ => Self::parse_posix(s),
+        }
+    }
+
+    pub fn parse_posix(s: &str) -> Option<Self> {
+        let mut rest = s;
+        take_tz_name(&mut rest)?;
+        // POSIX offsets are west of UTC, so the sign is inverted
+        let std_offset = -take_tz_offset(&mut rest)?;
+        if rest.is_empty() {
+            return Some(Self {
+                std_offset,
+                dst: None,
+            });
+        }
+        take_tz_name(&mut rest)?;
+        let dst_offset = if rest.starts_with(',') {
+            std_offset + 3_600
+        } else {
+            -take_tz_offset(&mut rest)?
+        };
+        let (start, end) = rest.strip_prefix(',')?.split_once(',')?;
+        Some(Self {
+            std_offset,
+            dst: Some((
+                dst_offset,
+                TransitionRule::parse(start)?,
+                TransitionRule::parse(end)?,
+            )),
+        })
+    }
+
+    // convert local microseconds since Unix epoch to UTC
+    pub fn to_utc(&self, local_micros: i64) -> i64 {
+        let local_secs = local_micros.div_euclid(MICROS_PER_SEC);
+        let offset = match &self.dst {
+            Some((dst_offset, start, end)) => {
+                let year = year_from_days(local_secs.div_euclid(86_400));
+                let (start, end) = (start.local_secs(year), end.local_secs(year));
+                let in_dst = if start < end {
+                    local_secs >= start && local_secs < end
+                } else {
+                    local_secs >= start || local_secs < end
+                };
+                if in_dst {
+                    *dst_offset
+                } else {
+                    self.std_offset
+                }
+            }
+            None => self.std_offset,
+        };
+        local_micros - offset * MICROS_PER_SEC
+    }
+}
+
+// take a POSIX TZ zone name, e.g. "CET" or "<+03>"
+pub fn take_tz_name(input: &mut &str) -> Option<()> {
+    let len = if let Some(rest) = input.strip_prefix('<') {
+        rest.find('>')? + 2
+    } else {
+        input.chars().take_while(char::is_ascii_alphabetic).count()
+    };
+    if len < 3 {
+        return None;
+    }
+    *input = &input[len..];
+    Some(())
+}
+
+// take a "[+-]hh[:mm[:ss]]" offset in seconds from the input
+pub fn take_tz_offset(input: &mut &str) -> Option<i64> {
+    let len = input
+        .char_indices()
+        .find(|(idx, c)| !(c.is_ascii_digit() || *c == ':' || (*idx == 0 && "+-".contains(*c))))
+        .map_or(input.len(), |(idx, _)| idx);
+    let offset = parse_tz_offset(&input[..len])?;
+    *input = &input[len..];
+    Some(offset)
+}
+
+// parse a "[+-]hh[:mm[:ss]]" or "[+-]hhmm" offset in seconds
+pub fn parse_tz_offset(s: &str) -> Option<i64> {
+    let (sign, s) = match s.strip_prefix('-') {
+        Some(s) => (-1, s),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let mut parts = if s.len() == 4 && !s.contains(':') {
+        vec![&s[..2], &s[2..]]
+    } else {
+        s.split(':').collect()
+    };
+    if parts.is_empty() || parts.len() > 3 {
+        return None;
+    }
+    parts.resize(3, "0");
+    let mut secs = 0;
+    for (part, unit) in parts.iter().zip([3_600, 60, 1]) {
+        let v: i64 = part.parse().ok()?;
+        if unit != 3_600 && v > 59 {
+            return None;
+        }
+        secs += v * unit;
+    }
+    Some(sign * secs)
+}
+
+// parse a gviz date or datetime value, e.g. "Date(2024,0,31)" or
+// "Date(2024,0,31,13,5,0)", note the month is 0-based
+pub fn parse_gviz_date(s: &str) -> Option<DateTimeParts> {
+    let args = s.strip_prefix("Date(")?.strip_suffix(')')?;
+    let args = args
+        .split(',')
+        .map(|v| v.trim().parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if args.len() < 3 || args.len() > 7 {
+        return None;
+    }
+    let arg = |idx: usize| args.get(idx).map_or(Some(0), |v| u32::try_from(*v).ok());
+    Some(DateTimeParts {
+        year: args[0],
+        month: arg(1)? + 1,
+        day: arg(2)?,
+        hour: arg(3)?,
+        minute: arg(4)?,
+        second: arg(5)?,
+        micro: arg(6)? * 1_000,
+    })
+}
+
+pub const MONTH_NAMES: [&str; 12] = [
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+
+// take an unsigned number of 1 up to max_digits digits from the input
+pub fn take_digits(input: &mut &str, max_digits: usize) -> Option<i64> {
+    let len = input
+        .bytes()
+        .take(max_digits)
+        .take_while(u8::is_ascii_digit)
+        .count();
+    if len == 0 {
+        return None;
+    }
+    let (digits, rest) = input.split_at(len);
+    *input = rest;
+    digits.parse().ok()
+}
+
+// take an English month name or its 3-letter abbreviation from the input
+pub fn take_month_name(input: &mut &str) -> Option<u32> {
+    let lower = input.to_ascii_lowercase();
+    for (idx, name) in MONTH_NAMES.iter().enumerate() {
+        for len in [name.len(), 3] {
+            if lower.starts_with(&name[..len]) {
+                *input = &input[len..];
+                return Some(idx as u32 + 1);
+            }
+        }
+    }
+    None
+}
+
+// parse date and time text with a strftime-like format, supported specifiers
+// are %Y %y %m %d %e %H %I %M %S %f %.f %p %b %h %B %F %T %D %R and %%, a
+// space in the format matches any amount of whitespace
+pub fn parse_datetime(s: &str, fmt: &str) -> Option<DateTimeParts> {
+    let fmt = fmt
+        .replace("%F", "%Y-%m-%d")
+        .replace("%T", "%H:%M:%S")
+        .replace("%D", "%m/%d/%y")
+        .replace("%R", "%H:%M");
+    let mut parts = DateTimeParts::default();
+    let mut hour12 = None;
+    let mut pm = None;
+    let mut input = s.trim_matches(is_space);
+    let mut spec = fmt.chars();
+
+    while let Some(c) = spec.next() {
+        if c.is_whitespace() {
+            input = input.trim_start_matches(is_space);
+            continue;
+        }
+        if c != '%' {
+            input = input.strip_prefix(c)?;
+            continue;
+        }
+        match spec.next()? {
+            'Y' => {
+                let neg = input.starts_with('-');
+                if neg {
+                    input = &input[1..];
+                }
+                let year = take_digits(&mut input, 4)?;
+                parts.year = if neg { -year } else { year };
+            }
+            'y' => {
+                let year = take_digits(&mut input, 2)?;
+                parts.year = if year < 69 { 2000 + year } else { 1900 + year };
+            }
+            'm' => parts.month = take_digits(&mut input, 2)? as u32,
+            'd' => parts.day = take_digits(&mut input, 2)? as u32,
+            'e' => {
+                input = input.trim_start_matches(' ');
+                parts.day = take_digits(&mut input, 2)? as u32;
+            }
+            'H' => parts.hour = take_digits(&mut input, 2)? as u32,
+            'I' => hour12 = Some(take_digits(&mut input, 2)? as u32),
+            'M' => parts.minute = take_digits(&mut input, 2)? as u32,
+            'S' => parts.second = take_digits(&mut input, 2)? as u32,
+            'f' => parts.micro = take_fraction(&mut input)?,
+            '.' => {
+                // optional fraction of second, e.g. "%S%.f"
+                if spec.next()? != 'f' {
+                    return None;
+                }
+                if let Some(rest) = input.strip_prefix('.') {
+                    input = rest;
+                    parts.micro = take_fraction(&mut input)?;
+                }
+            }
+            'p' => {
+                let lower = input.get(..2)?.to_ascii_lowercase();
+                pm = match lower.as_str() {
+                    "am" => Some(false),
+                    "pm" => Some(true),
+                    _ => return None,
+                };
+                input = &input[2..];
+            }
+            'b' | 'h' | 'B' => parts.month = take_month_name(&mut input)?,
+            '%' => input = input.strip_prefix('%')?,
+            _ => return None,
+        }
+    }
+    if !input.is_empty() {
+        return None;
+    }
+
+    if let Some(hour) = hour12 {
+        if !(1..=12).contains(&hour) {
+            return None;
+        }
+        parts.hour = hour % 12 + if pm == Some(true) { 12 } else { 0 };
+    }
+    Some(parts)
+}
+
+// take the digits of a fraction of second from the input as microseconds
+pub fn take_fraction(input: &mut &str) -> Option<u32> {
+    let len = input.bytes().take_while(u8::is_ascii_digit).count();
+    if len == 0 {
+        return None;
+    }
+    let (digits, rest) = input.split_at(len);
+    *input = rest;
+    let micro = format!("{:0<6}", &digits[..len.min(6)]);
+    micro.parse().ok()
+}
@@ -0,0 +1,70 @@
+// base URL failover, the base_url server option can list mirrors of the gviz
+// endpoint, e.g. a regional mirror or an internal caching proxy, which are
+// tried in order when a request cannot connect, the first one that answers is
+// tried first by the following requests
+use std::sync::{Mutex, MutexGuard};
+
+use crate::bindings::supabase::wrappers::{http, types::FdwError, utils};
+
+static BASE_URLS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn base_urls() -> MutexGuard<'static, Vec<String>> {
+    BASE_URLS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// set the base URLs of the instance, the first one is the primary
+pub fn configure(urls: Vec<String>) {
+    *base_urls() = urls;
+}
+
+// send a GET request, failing over to the other base URLs on connection
+// errors, HTTP error responses are returned as is and so are requests to
+// other URLs
+pub fn get(req: &http::Request) -> Result<http::Response, FdwError> {
+    let urls = base_urls().clone();
+    let (resp, idx) = failover(req, &urls, http::get, utils::report_warning)?;
+    if idx > 0 {
+        base_urls().rotate_left(idx);
+    }
+    Ok(resp)
+}
+
+// send a request with the given sender to the base URLs in order until one
+// answers, which is returned with the response, warning about each failover
+pub fn failover(
+    req: &http::Request,
+    urls: &[String],
+    mut send: impl FnMut(&http::Request) -> Result<http::Response, FdwError>,
+    mut warn: impl FnMut(&str),
+) -> Result<(http::Response, usize), FdwError> {
+    // the longest base URL the request is made of, any of them as the
+    // request URL may have been made before a failover
+    let Some(path) = urls
+        .iter()
+        .filter_map(|base| req.url.strip_prefix(base.as_str()))
+        .min_by_key(|path| path.len())
+    else {
+        return send(req).map(|resp| (resp, 0));
+    };
+
+    let mut last_err = FdwError::default();
+    for (idx, base) in urls.iter().enumerate() {
+        let req = http::Request {
+            url: format!("{}{}", base, path),
+            ..req.clone()
+        };
+        match send(&req) {
+            Ok(resp) => return Ok((resp, idx)),
+            Err(e) => {
+                if let Some(next) = urls.get(idx + 1) {
+                    warn(&format!(
+                        "cannot connect to {}, failing over to {}: {}",
+                        base, next, e
+                    ));
+                }
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
+}
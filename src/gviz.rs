@@ -0,0 +1,1252 @@
+// gviz endpoint: response rows, query building and the request itself
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::api::{fetch_spreadsheet, GridData, SheetGrid};
+use crate::bindings::supabase::wrappers::{
+    http,
+    types::{Cell, FdwError, FdwResult, TypeOid},
+    utils,
+};
+#[cfg(feature = "csv")]
+use crate::column::cell_text;
+use crate::column::{
+    is_numeric_type, parse_bool_text, type_name, CellProperty, Coercion, ColumnSpec, VirtualColumn,
+};
+#[cfg(feature = "csv")]
+use crate::csv::{parse_csv_rows, CsvFormat};
+use crate::datetime::parse_datetime;
+use crate::options::{parse_bool_option, parse_list_option, OptionLookup};
+use crate::retry;
+use crate::source::Source;
+
+// gviz response, deserialized straight into the shape the scan reads so the
+// generic JSON tree is only built for cell values
+#[derive(Debug, Deserialize)]
+pub struct GvizResponse {
+    // "ok", "warning" or "error"
+    #[serde(default)]
+    pub status: String,
+    pub table: Option<GvizTable>,
+    #[serde(default)]
+    pub warnings: Vec<GvizMessage>,
+    #[serde(default)]
+    pub errors: Vec<GvizMessage>,
+}
+
+impl GvizResponse {
+    // the rows are cut off at the row cap of the endpoint, without an error
+    pub fn is_truncated(&self) -> bool {
+        self.warnings.iter().any(|w| w.reason == "data_truncated")
+    }
+
+    // number of sheet rows above the data rows taken as header rows
+    pub fn header_rows(&self) -> usize {
+        self.table
+            .as_ref()
+            .map_or(0, |table| table.parsed_num_headers)
+    }
+
+    // result columns, with trimmed header labels
+    pub fn columns(&self) -> Vec<GvizColumn> {
+        self.table
+            .iter()
+            .flat_map(|table| &table.cols)
+            .map(|col| GvizColumn {
+                label: col.label.trim().to_owned(),
+                kind: col.kind.clone(),
+            })
+            .collect()
+    }
+
+    // the errors of a failed query, e.g. "invalid_query: INVALID_QUERY,
+    // Invalid query: NO_COLUMN: Z" for a bad tq
+    pub fn error(&self) -> Option<FdwError> {
+        if self.status != "error" {
+            return None;
+        }
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .map(|e| {
+                let details: Vec<&str> = [e.message.as_deref(), e.detailed_message.as_deref()]
+                    .into_iter()
+                    .flatten()
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                format!("{}: {}", e.reason, details.join(", "))
+            })
+            .collect();
+        Some(match errors.is_empty() {
+            true => "gviz query failed without a reason".to_owned(),
+            false => format!("gviz query failed, {}", errors.join("; ")),
+        })
+    }
+
+    // extract source rows from the response
+    pub fn into_rows(self) -> Result<Vec<GvizRow>, FdwError> {
+        if let Some(e) = self.error() {
+            return Err(e);
+        }
+        self.table
+            .map(|table| table.rows)
+            .ok_or("cannot get rows from response".to_owned())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GvizMessage {
+    #[serde(default)]
+    pub reason: String,
+    pub message: Option<String>,
+    pub detailed_message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GvizTable {
+    #[serde(default)]
+    pub cols: Vec<GvizColumn>,
+    #[serde(default)]
+    pub rows: Vec<GvizRow>,
+    #[serde(rename = "parsedNumHeaders", default)]
+    pub parsed_num_headers: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GvizColumn {
+    // text of the header row above the column, empty if there is none
+    #[serde(default)]
+    pub label: String,
+    // type gviz declares for the column from most of its cells, e.g.
+    // "number", "string", "boolean", "date", "datetime" or "timeofday"
+    #[serde(rename = "type", default)]
+    pub kind: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GvizRow {
+    #[serde(default)]
+    pub c: Vec<Option<GvizCell>>,
+    // cells of the cell_data columns by target column, read from the grid
+    // data rather than gviz
+    #[serde(skip)]
+    pub cell_data: Vec<Option<Cell>>,
+    // 1-based sheet row of the row, if the _row_number or _row_version column
+    // is read
+    #[serde(skip)]
+    pub row_number: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GvizCell {
+    #[serde(default)]
+    pub v: JsonValue,
+    // formatted value, as displayed in the sheet
+    pub f: Option<String>,
+}
+
+impl GvizCell {
+    // the cell has neither a value nor a formatted text
+    pub fn is_blank(&self) -> bool {
+        self.v.is_null() && self.f.as_deref().is_none_or(str::is_empty)
+    }
+}
+
+// make a gviz select clause computing expression columns remotely, result
+// columns keep the source positions so other columns are selected as is
+pub fn select_clause(tgt_cols: &[ColumnSpec]) -> Option<String> {
+    if tgt_cols.iter().all(|c| c.expr.is_none()) {
+        return None;
+    }
+    let len = tgt_cols.iter().map(|c| c.src_idx + 1).max().unwrap_or(0);
+    let cols: Vec<String> = (0..len)
+        .map(|idx| {
+            tgt_cols
+                .iter()
+                .find(|c| c.src_idx == idx && c.virtual_col.is_none())
+                .and_then(|c| c.expr.clone())
+                .unwrap_or_else(|| column_letters(idx as i64))
+        })
+        .collect();
+    Some(format!("select {}", cols.join(", ")))
+}
+
+// make a gviz query literal of an option value for a target column type,
+// e.g. date '2024-01-31' for a date column
+pub fn gviz_literal(type_oid: TypeOid, value: &str) -> Result<String, FdwError> {
+    let invalid = || format!("invalid {} value '{}'", type_name(type_oid), value);
+    let datetime = || {
+        ["%F %T%.f", "%FT%T%.f", "%F"]
+            .iter()
+            .find_map(|fmt| parse_datetime(value, fmt))
+            .ok_or_else(invalid)
+    };
+    match type_oid {
+        TypeOid::Bool => match parse_bool_text(value) {
+            Some(v) => Ok(v.to_string()),
+            None => Err(invalid()),
+        },
+        TypeOid::String if !value.contains('"') => Ok(format!("\"{}\"", value)),
+        TypeOid::String if !value.contains('\'') => Ok(format!("'{}'", value)),
+        // gviz string literals have no escapes
+        TypeOid::String => Err(format!("value '{}' cannot have both quote types", value)),
+        TypeOid::Date => {
+            let t = datetime()?;
+            Ok(format!("date '{:04}-{:02}-{:02}'", t.year, t.month, t.day))
+        }
+        TypeOid::Timestamp | TypeOid::Timestamptz => {
+            let t = datetime()?;
+            Ok(format!(
+                "datetime '{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}'",
+                t.year,
+                t.month,
+                t.day,
+                t.hour,
+                t.minute,
+                t.second,
+                t.micro / 1000
+            ))
+        }
+        _ if is_numeric_type(type_oid) => match value.trim().parse::<f64>() {
+            Ok(v) if v.is_finite() => Ok(v.to_string()),
+            _ => Err(invalid()),
+        },
+        _ => Err(format!(
+            "{} column cannot be a watermark",
+            type_name(type_oid)
+        )),
+    }
+}
+
+// column letters of a 0-based column index, e.g. 27 to "AB"
+pub fn column_letters(idx: i64) -> String {
+    let mut ret = Vec::new();
+    let mut n = idx.max(0) + 1;
+    while n > 0 {
+        n -= 1;
+        ret.push(b'A' + (n % 26) as u8);
+        n /= 26;
+    }
+    ret.iter().rev().map(|&b| b as char).collect()
+}
+
+// 0-based column index of column letters, e.g. "AB" to 27, None if they are
+// not column letters
+pub fn column_index(letters: &str) -> Option<usize> {
+    if letters.is_empty() || letters.len() > 3 {
+        return None;
+    }
+    letters
+        .bytes()
+        .try_fold(0, |idx, b| match b {
+            b'A'..=b'Z' => Some(idx * 26 + (b - b'A') as usize + 1),
+            _ => None,
+        })
+        .map(|n: usize| n - 1)
+}
+
+// 0-based row and column of the top-left cell of an A1 range, e.g. "B2:D10"
+// to (1, 1), a range of whole columns starts at the first row and a range of
+// whole rows at the first column
+pub fn a1_start(range: &str) -> (usize, usize) {
+    let start = range.split(':').next().unwrap_or_default().trim();
+    let letters: String = start
+        .chars()
+        .take_while(char::is_ascii_alphabetic)
+        .collect();
+    let col = column_index(&letters.to_ascii_uppercase()).unwrap_or(0);
+    let row = start[letters.len()..]
+        .parse::<usize>()
+        .map_or(0, |row| row.saturating_sub(1));
+    (row, col)
+}
+
+// get the header_rows option, the number of rows above the data in the range
+pub fn header_rows(opts: &impl OptionLookup) -> Result<Option<usize>, FdwError> {
+    match opts.lookup("header_rows") {
+        Some(rows) => match rows.parse::<usize>() {
+            Ok(rows) => Ok(Some(rows)),
+            _ => Err(format!("invalid header_rows '{}'", rows)),
+        },
+        None => Ok(None),
+    }
+}
+
+// percent-encode a string for use as URL query parameter value
+pub fn encode_query(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                ret.push(b as char)
+            }
+            _ => ret.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    ret
+}
+
+// gviz source, rows of a sheet are queried by a gviz query whose clauses are
+// built from table options and target columns
+#[derive(Debug, Default)]
+pub struct GvizSource {
+    // max number of rows buffered at a time, fetch all rows at once if not set
+    fetch_size: Option<usize>,
+    // max number of requests per spreadsheet, unlimited if not set
+    max_pages: Option<usize>,
+    // rows per request once a response is truncated, at most fetch_size
+    page_size: Option<usize>,
+    // number of requests made for the current spreadsheet
+    pages: usize,
+    // number of rows fetched from the current spreadsheet before this page
+    fetched: usize,
+    // max number of rows fetched per spreadsheet, all rows if not set
+    max_rows: Option<usize>,
+    // number of leading data rows skipped
+    offset_rows: usize,
+    // the last response was truncated, so there are more rows to fetch
+    truncated: bool,
+    // max number of columns per request, all columns at once if not set
+    column_chunk_size: Option<usize>,
+    // column ranges fetched by separate requests for wide sheets
+    column_chunks: Vec<ColumnChunk>,
+    // raw gviz query
+    tq: Option<String>,
+    // column and value only rows past which are fetched
+    watermark: (Option<String>, Option<String>),
+    // select clause of the target columns, e.g. "select A, B, C"
+    select: Option<String>,
+    // condition of the watermark, e.g. "A > 10"
+    condition: Option<String>,
+    // condition of the filter view of the current spreadsheet
+    filter: Option<String>,
+    // header labels the first columns of each spreadsheet must have
+    expected_headers: Option<Vec<String>>,
+    // number of header rows, guessed by gviz if not set
+    headers: Option<usize>,
+    // header labels of the current spreadsheet by source position
+    labels: Vec<String>,
+    // target columns read the sheet columns at their positions, rather than
+    // a reshape of the sheet
+    sheet_columns: bool,
+    // query the CSV output instead if a response cannot be parsed
+    csv_fallback: bool,
+    // fill the cells of merged regions with the value of their top-left cell
+    fill_merged: bool,
+    // the rows are filtered by a filter view
+    filter_view: bool,
+    // target column index, sheet cell position and property of the cell_data
+    // columns
+    cell_data: Vec<(usize, usize, CellProperty)>,
+    // the _row_number or _row_version column is read, so rows are numbered
+    // by sheet position
+    row_numbers: bool,
+    // tab and range of the current spreadsheet in its sheet grid
+    grid: Option<SheetGrid>,
+    // number of header rows of the current spreadsheet, above its data rows
+    header_rows: usize,
+    // values of the top-left cells of the merges seen so far, by merge index
+    merged: HashMap<usize, GvizCell>,
+}
+
+// columns of a sheet fetched by one request
+#[derive(Debug)]
+struct ColumnChunk {
+    // source positions of the result columns
+    positions: Vec<usize>,
+    // select clause of the columns, e.g. "select D, E"
+    select: String,
+}
+
+impl GvizSource {
+    pub fn new(opts: &impl OptionLookup) -> Result<Self, FdwError> {
+        // rows per request, used to cap the number of rows buffered in memory
+        let fetch_size = match opts.lookup("fetch_size") {
+            Some(size) => match size.parse::<usize>() {
+                Ok(size) if size > 0 => Some(size),
+                _ => return Err(format!("invalid fetch_size '{}'", size)),
+            },
+            None => None,
+        };
+        let max_pages = match opts.lookup("max_pages") {
+            Some(max) => match max.parse::<usize>() {
+                Ok(max) if max > 0 => Some(max),
+                _ => return Err(format!("invalid max_pages '{}'", max)),
+            },
+            None => None,
+        };
+        // rows sampled from the sheet, e.g. for a preview of a huge sheet
+        let max_rows = match opts.lookup("max_rows") {
+            Some(max) => match max.parse::<usize>() {
+                Ok(max) if max > 0 => Some(max),
+                _ => return Err(format!("invalid max_rows '{}'", max)),
+            },
+            None => None,
+        };
+        let offset_rows = match opts.lookup("offset_rows") {
+            Some(offset) => match offset.parse::<usize>() {
+                Ok(offset) => offset,
+                _ => return Err(format!("invalid offset_rows '{}'", offset)),
+            },
+            None => 0,
+        };
+        // columns per request, used to keep responses of wide sheets small
+        let column_chunk_size = match opts.lookup("column_chunk_size") {
+            Some(size) => match size.parse::<usize>() {
+                Ok(size) if size > 0 => Some(size),
+                _ => return Err(format!("invalid column_chunk_size '{}'", size)),
+            },
+            None => None,
+        };
+        let tq = opts.lookup("tq");
+        if tq.is_some() && opts.lookup("filter_view").is_some() {
+            return Err("tq cannot be used with filter_view".to_owned());
+        }
+        // header labels of the sheet, checked so that a restructured sheet
+        // fails the scan instead of shifting the data under the columns
+        let expected_headers = match opts.lookup("expected_headers") {
+            Some(_) if tq.is_some() => {
+                return Err("expected_headers cannot be used with tq".to_owned());
+            }
+            Some(headers) => {
+                let headers = parse_list_option(&headers);
+                if headers.is_empty() {
+                    return Err("expected_headers cannot be empty".to_owned());
+                }
+                Some(headers)
+            }
+            None => None,
+        };
+        // the first row is the header row of expected_headers and of the
+        // unpivot columns, and a transposed sheet has its labels in a column
+        // rather than a row
+        let header_row = expected_headers.is_some() || opts.lookup("unpivot_columns").is_some();
+        let headers = match (
+            header_row,
+            parse_bool_option("transpose", opts.lookup("transpose"))?,
+        ) {
+            (true, true) if expected_headers.is_some() => {
+                return Err("expected_headers cannot be used with transpose".to_owned());
+            }
+            (true, true) => {
+                return Err("unpivot_columns cannot be used with transpose".to_owned());
+            }
+            (true, false) => Some(1),
+            (false, true) => Some(0),
+            (false, false) => None,
+        };
+        // header rows given for writes, which cannot guess them, are read
+        // the same way
+        let headers = match (header_rows(opts)?, headers) {
+            (Some(rows), Some(headers)) if rows != headers => {
+                return Err(
+                    "header_rows cannot be used with expected_headers, unpivot_columns or \
+                     transpose"
+                        .to_owned(),
+                );
+            }
+            (rows, headers) => rows.or(headers),
+        };
+        let sheet_columns = opts.lookup("layout").is_none_or(|v| v == "rows")
+            && headers != Some(0)
+            && opts.lookup("unpivot_columns").is_none();
+        let csv_fallback = parse_bool_option("csv_fallback", opts.lookup("csv_fallback"))?;
+        let fill_merged = parse_bool_option("fill_merged", opts.lookup("fill_merged"))?;
+        #[cfg(not(feature = "csv"))]
+        if csv_fallback {
+            return Err(
+                "csv_fallback is not built into this FDW, enable the csv cargo feature".to_owned(),
+            );
+        }
+        Ok(Self {
+            fetch_size,
+            max_pages,
+            page_size: fetch_size,
+            max_rows,
+            offset_rows,
+            column_chunk_size,
+            tq,
+            watermark: (
+                opts.lookup("watermark_column"),
+                opts.lookup("watermark_value"),
+            ),
+            expected_headers,
+            headers,
+            sheet_columns,
+            csv_fallback,
+            fill_merged,
+            filter_view: opts.lookup("filter_view").is_some(),
+            ..Default::default()
+        })
+    }
+
+    // start a page after the fetched rows, the paging is reset at the first
+    // request of a spreadsheet
+    fn start_page(&mut self, fetched: usize) {
+        if fetched == 0 {
+            self.pages = 0;
+            self.page_size = self.fetch_size;
+        }
+        self.fetched = fetched;
+    }
+
+    // number of rows requested by the page, capped by the rows left to
+    // max_rows, unlimited if not set
+    fn page_limit(&self) -> Option<usize> {
+        let left = self.max_rows.map(|max| max.saturating_sub(self.fetched));
+        match (self.page_size, left) {
+            (Some(size), Some(left)) => Some(size.min(left)),
+            (size, left) => size.or(left),
+        }
+    }
+
+    // gviz query clauses selecting the given columns of the rows passing the
+    // watermark and the filter view, a raw tq is used as is
+    fn query(&self, select: Option<&str>) -> Option<String> {
+        if self.tq.is_some() {
+            return self.tq.clone();
+        }
+        let conds: Vec<&str> = self
+            .condition
+            .iter()
+            .chain(&self.filter)
+            .map(String::as_str)
+            .collect();
+        let where_clause = match conds.as_slice() {
+            [] => None,
+            [cond] => Some(format!("where {}", cond)),
+            conds => Some(format!("where ({})", conds.join(") and ("))),
+        };
+        let clauses: Vec<&str> = select.into_iter().chain(where_clause.as_deref()).collect();
+        (!clauses.is_empty()).then(|| clauses.join(" "))
+    }
+
+    // make the request of the page with the query clauses, max_rows and
+    // offset_rows are applied here so rows past them are never downloaded
+    fn request(&self, url: &str, select: Option<&str>) -> http::Request {
+        let mut tq: Vec<String> = self.query(select).into_iter().collect();
+        let offset = self.offset_rows + self.fetched;
+        match self.page_limit() {
+            Some(limit) => tq.push(format!("limit {} offset {}", limit, offset)),
+            None if offset > 0 => tq.push(format!("offset {}", offset)),
+            None => {}
+        }
+        let mut url = match tq.is_empty() {
+            true => url.to_owned(),
+            false => format!("{}&tq={}", url, encode_query(&tq.join(" "))),
+        };
+        if let Some(headers) = self.headers {
+            url.push_str(&format!("&headers={}", headers));
+        }
+
+        // make up request headers
+        let headers: Vec<(String, String)> = vec![
+            ("user-agent".to_owned(), "Sheets FDW".to_owned()),
+            // header to make JSON response more cleaner
+            ("x-datasource-auth".to_owned(), "true".to_owned()),
+        ];
+
+        http::Request {
+            method: http::Method::Get,
+            url,
+            headers,
+            body: String::default(),
+        }
+    }
+
+    // send the request of a page and parse its response, whose columns are at
+    // the given source positions, the undocumented JSON wrapper of gviz may
+    // change without notice, so with csv_fallback a response that cannot be
+    // parsed is requested again as CSV
+    fn get_response(
+        &self,
+        req: &http::Request,
+        positions: &[usize],
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<GvizResponse, FdwError> {
+        let resp = retry::get(req)?;
+        check_status(&resp)?;
+        match parse_response(&resp.body) {
+            Err(e) if self.csv_fallback => self.get_csv_response(req, e, positions, tgt_cols),
+            resp => resp,
+        }
+    }
+
+    // request a page again as CSV after its response could not be parsed
+    #[cfg(feature = "csv")]
+    fn get_csv_response(
+        &self,
+        req: &http::Request,
+        e: FdwError,
+        positions: &[usize],
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<GvizResponse, FdwError> {
+        utils::report_warning(&format!(
+            "cannot parse gviz response, querying CSV instead: {}",
+            e
+        ));
+        let req = http::Request {
+            url: req.url.replace("tqx=out:json", "tqx=out:csv"),
+            ..req.clone()
+        };
+        let resp = retry::get(&req)?;
+        http::error_for_status(&resp)?;
+        let types: Vec<Option<TypeOid>> = positions
+            .iter()
+            .map(|&idx| {
+                tgt_cols
+                    .iter()
+                    .find(|c| c.src_idx == idx && c.virtual_col.is_none())
+                    .map(|c| c.type_oid)
+            })
+            .collect();
+        parse_csv_response(&resp.body, &types)
+    }
+
+    #[cfg(not(feature = "csv"))]
+    fn get_csv_response(
+        &self,
+        _req: &http::Request,
+        e: FdwError,
+        _positions: &[usize],
+        _tgt_cols: &[ColumnSpec],
+    ) -> Result<GvizResponse, FdwError> {
+        Err(e)
+    }
+
+    // the columns of a wide sheet are fetched in chunks of column_chunk_size,
+    // whose rows are stitched back together by row index
+    fn fetch_page(
+        &mut self,
+        url: &str,
+        fetched: usize,
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        if self.column_chunks.is_empty() {
+            let req = self.build_request(url, fetched);
+            let len = tgt_cols.iter().map(|c| c.src_idx + 1).max().unwrap_or(0);
+            let positions: Vec<usize> = (0..len).collect();
+            let resp = self.get_response(&req, &positions, tgt_cols)?;
+            return self.read_page(resp, tgt_cols);
+        }
+        let reqs = self.chunk_requests(url, fetched);
+        let mut resps = Vec::with_capacity(reqs.len());
+        for (chunk, req) in self.column_chunks.iter().zip(&reqs) {
+            resps.push(self.get_response(req, &chunk.positions, tgt_cols)?);
+        }
+        self.read_chunks(resps, tgt_cols)
+    }
+
+    // start a page after the fetched rows and make the requests of its column
+    // chunks
+    pub fn chunk_requests(&mut self, url: &str, fetched: usize) -> Vec<http::Request> {
+        self.start_page(fetched);
+        self.column_chunks
+            .iter()
+            .map(|chunk| self.request(url, Some(&chunk.select)))
+            .collect()
+    }
+
+    // read the responses of the column chunks of a page, in the order of the
+    // chunks, stitching their rows back together by row index
+    pub fn read_chunks(
+        &mut self,
+        resps: Vec<GvizResponse>,
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        let len = self
+            .column_chunks
+            .iter()
+            .flat_map(|chunk| &chunk.positions)
+            .map(|&idx| idx + 1)
+            .max()
+            .unwrap_or(0);
+        let mut cols = vec![None; len];
+        let mut parts = Vec::with_capacity(resps.len());
+        let mut truncated = false;
+        for (chunk, resp) in self.column_chunks.iter().zip(resps) {
+            truncated |= resp.is_truncated();
+            if self.fetched == 0 {
+                self.header_rows = resp.header_rows();
+            }
+            for (&idx, col) in chunk.positions.iter().zip(resp.columns()) {
+                cols[idx] = Some(col);
+            }
+            parts.push(resp.into_rows()?);
+        }
+        self.read_columns(cols, tgt_cols)?;
+
+        // chunks truncated at different rows are cut to the shortest one, the
+        // rest of the rows are fetched by the next page
+        let rows = parts.iter().map(Vec::len).min().unwrap_or(0);
+        if !truncated && parts.iter().any(|part| part.len() != rows) {
+            return Err("column chunks have different numbers of rows".to_owned());
+        }
+        let mut src_rows: Vec<GvizRow> = (0..rows)
+            .map(|_| GvizRow {
+                c: (0..len).map(|_| None).collect(),
+                ..Default::default()
+            })
+            .collect();
+        for (chunk, part) in self.column_chunks.iter().zip(parts) {
+            for (src_row, row) in src_rows.iter_mut().zip(part) {
+                for (&idx, cell) in chunk.positions.iter().zip(row.c) {
+                    src_row.c[idx] = cell;
+                }
+            }
+        }
+
+        self.end_page(rows, truncated)?;
+        Ok(src_rows)
+    }
+
+    // read the columns and rows of the response of a page
+    fn read_page(
+        &mut self,
+        resp: GvizResponse,
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        self.read_columns(resp.columns().into_iter().map(Some).collect(), tgt_cols)?;
+        if self.fetched == 0 {
+            self.header_rows = resp.header_rows();
+        }
+        let truncated = resp.is_truncated();
+        let rows = resp.into_rows()?;
+        self.end_page(rows.len(), truncated)?;
+        Ok(rows)
+    }
+
+    // keep the header labels of the columns of the first page of a
+    // spreadsheet, given by source position, and check the columns against
+    // expected_headers and the target columns
+    fn read_columns(
+        &mut self,
+        cols: Vec<Option<GvizColumn>>,
+        tgt_cols: &[ColumnSpec],
+    ) -> FdwResult {
+        if self.fetched > 0 {
+            return Ok(());
+        }
+        let labels: Vec<Option<String>> = cols
+            .iter()
+            .map(|col| col.as_ref().map(|col| col.label.clone()))
+            .collect();
+        self.check_headers(&labels)?;
+        self.check_types(&cols, tgt_cols)?;
+        self.labels = labels.into_iter().map(Option::unwrap_or_default).collect();
+        Ok(())
+    }
+
+    // check the column types gviz declares against the target column types,
+    // gviz returns the cells of other types than the declared one as empty
+    // cells, so a mismatch is a warning, or an error in strict coercion
+    fn check_types(&self, cols: &[Option<GvizColumn>], tgt_cols: &[ColumnSpec]) -> FdwResult {
+        // the result columns of a raw query or a reshape are not sheet columns
+        if self.tq.is_some() || !self.sheet_columns {
+            return Ok(());
+        }
+        for tgt_col in tgt_cols
+            .iter()
+            .filter(|c| c.virtual_col.is_none() && c.expr.is_none())
+        {
+            let Some(Some(col)) = cols.get(tgt_col.src_idx) else {
+                continue;
+            };
+            let letters = column_letters(tgt_col.src_idx as i64);
+            if let Some(msg) = tgt_col.declared_type_mismatch(&col.kind, &letters) {
+                if tgt_col.coercion == Coercion::Strict {
+                    return Err(msg);
+                }
+                utils::report_warning(&msg);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_headers(&self, labels: &[Option<String>]) -> FdwResult {
+        let Some(expected) = &self.expected_headers else {
+            return Ok(());
+        };
+        let diffs: Vec<String> = expected
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, name)| {
+                let col = column_letters(idx as i64);
+                match labels.get(idx).and_then(Option::as_deref) {
+                    Some(label) if label == name => None,
+                    Some(label) => Some(format!(
+                        "  column {}: expected '{}', found '{}'",
+                        col, name, label
+                    )),
+                    None => Some(format!("  column {}: expected '{}', found none", col, name)),
+                }
+            })
+            .collect();
+        match diffs.is_empty() {
+            true => Ok(()),
+            false => Err(format!(
+                "header row does not match expected_headers\n{}",
+                diffs.join("\n")
+            )),
+        }
+    }
+
+    // a truncated page is followed by pages of the number of rows it has,
+    // until all rows are fetched or max_pages is reached
+    fn end_page(&mut self, rows: usize, truncated: bool) -> FdwResult {
+        self.pages += 1;
+        self.truncated = truncated;
+
+        if self.truncated {
+            if self.tq.is_some() {
+                return Err(
+                    "response is truncated, use fetch_size instead of tq to fetch all rows"
+                        .to_owned(),
+                );
+            }
+            if rows == 0 {
+                return Err("response is truncated but has no rows".to_owned());
+            }
+            self.page_size = Some(self.page_size.map_or(rows, |s| s.min(rows)));
+        }
+
+        if !self.is_last_chunk(rows) && self.max_pages.is_some_and(|max| self.pages >= max) {
+            utils::report_warning(&format!(
+                "stopped after max_pages {} requests, the remaining rows are not fetched",
+                self.pages
+            ));
+            self.truncated = false;
+            self.page_size = None;
+        }
+        Ok(())
+    }
+}
+
+// merged cells are filled by sheet position, each merged region gets the
+// value of its top-left cell, once that cell is among the rows read
+impl GvizSource {
+    // 0-based sheet row of the first row of the page
+    fn first_sheet_row(&self, grid: &SheetGrid) -> usize {
+        grid.row + self.header_rows + self.offset_rows + self.fetched
+    }
+
+    // Sheets v4 URL of the grid data of the cell_data columns for the rows of
+    // the page, one request per page, None if there is nothing to read
+    pub fn cell_data_url(&self, rows: usize) -> Option<String> {
+        let grid = self.grid.as_ref()?;
+        if self.cell_data.is_empty() || rows == 0 {
+            return None;
+        }
+        let cols = self.cell_data.iter().map(|&(_, src_idx, _)| src_idx);
+        let (first_col, last_col) = (cols.clone().min()?, cols.max()?);
+        let first_row = self.first_sheet_row(grid);
+        let range = format!(
+            "'{}'!{}{}:{}{}",
+            grid.title.replace('\'', "''"),
+            column_letters((grid.col + first_col) as i64),
+            first_row + 1,
+            column_letters((grid.col + last_col) as i64),
+            first_row + rows,
+        );
+        let mut fields: Vec<&str> = self
+            .cell_data
+            .iter()
+            .map(|(_, _, prop)| prop.fields())
+            .collect();
+        fields.sort_unstable();
+        fields.dedup();
+        let fields = format!(
+            "sheets.data(startRow,startColumn,rowData.values({}))",
+            fields.join(",")
+        );
+        Some(format!(
+            "{}?ranges={}&fields={}",
+            grid.url,
+            encode_query(&range),
+            encode_query(&fields)
+        ))
+    }
+
+    // set the cells of the cell_data columns of the rows of the page from
+    // their grid data, cells outside of it are NULL
+    pub fn set_cell_data(&self, rows: &mut [GvizRow], data: &GridData, len: usize) {
+        let Some(grid) = &self.grid else {
+            return;
+        };
+        let first_row = self.first_sheet_row(grid);
+        for (idx, row) in rows.iter_mut().enumerate() {
+            row.cell_data.resize(len, None);
+            let row_data = (first_row + idx)
+                .checked_sub(data.start_row)
+                .and_then(|i| data.row_data.get(i));
+            for &(tgt_idx, src_idx, prop) in &self.cell_data {
+                let col = grid.col + src_idx;
+                row.cell_data[tgt_idx] = row_data
+                    .zip(col.checked_sub(data.start_column))
+                    .and_then(|(row_data, i)| row_data.values.get(i))
+                    .and_then(|cell| prop.cell(cell));
+            }
+        }
+    }
+
+    // number the rows of the current page by their sheet row
+    pub fn set_row_numbers(&self, rows: &mut [GvizRow]) {
+        let Some(grid) = self.grid.as_ref().filter(|_| self.row_numbers) else {
+            return;
+        };
+        let first_row = self.first_sheet_row(grid);
+        for (idx, row) in rows.iter_mut().enumerate() {
+            row.row_number = Some(first_row + idx + 1);
+        }
+    }
+
+    // fill the cells of merged regions in the rows of the current page, whose
+    // first row follows the header rows, offset_rows and the rows fetched, the
+    // top-left values are kept across the pages of a spreadsheet
+    pub fn fill_merged_cells(&mut self, rows: &mut [GvizRow], tgt_cols: &[ColumnSpec]) {
+        let Some(grid) = self.grid.as_ref().filter(|_| self.fill_merged) else {
+            return;
+        };
+        let first_row = self.first_sheet_row(grid);
+        let mut positions: Vec<usize> = tgt_cols
+            .iter()
+            .filter(|c| c.virtual_col.is_none())
+            .map(|c| c.src_idx)
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        let span = |start: Option<i64>, end: Option<i64>, idx: usize| {
+            start.unwrap_or(0) as usize <= idx && end.is_none_or(|end| idx < end as usize)
+        };
+
+        for (idx, row) in rows.iter_mut().enumerate() {
+            let sheet_row = first_row + idx;
+            for (merge_idx, merge) in grid.merges.iter().enumerate() {
+                if !span(merge.start_row_index, merge.end_row_index, sheet_row) {
+                    continue;
+                }
+                let top = merge.start_row_index.unwrap_or(0) as usize;
+                let left = merge.start_column_index.unwrap_or(0) as usize;
+                if sheet_row == top {
+                    let cell = left.checked_sub(grid.col).and_then(|pos| row.c.get(pos));
+                    if let Some(Some(cell)) = cell {
+                        self.merged.insert(merge_idx, cell.clone());
+                    }
+                }
+                let Some(value) = self.merged.get(&merge_idx) else {
+                    continue;
+                };
+                for &pos in &positions {
+                    let col = grid.col + pos;
+                    if (sheet_row == top && col == left)
+                        || !span(merge.start_column_index, merge.end_column_index, col)
+                    {
+                        continue;
+                    }
+                    if row.c.len() <= pos {
+                        row.c.resize(pos + 1, None);
+                    }
+                    if row.c[pos].as_ref().is_none_or(GvizCell::is_blank) {
+                        row.c[pos] = Some(value.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Source for GvizSource {
+    fn reads_spreadsheets(&self) -> bool {
+        true
+    }
+
+    // only rows past the watermark are fetched in incremental scans, a raw
+    // gviz query is passed as is with its result columns mapped to the target
+    // columns by position
+    fn prepare(&mut self, tgt_cols: &[ColumnSpec]) -> FdwResult {
+        let has_expr = tgt_cols.iter().any(|c| c.expr.is_some());
+        if self.column_chunk_size.is_some() && (self.tq.is_some() || has_expr) {
+            return Err("column_chunk_size cannot be used with tq or expr".to_owned());
+        }
+        // merges and cell data are found by sheet position, so the rows must
+        // be the sheet rows in order without any left out before them, and
+        // the columns the sheet columns
+        self.cell_data = tgt_cols
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, c)| match c.virtual_col {
+                Some(VirtualColumn::CellData(prop)) => Some((idx, c.src_idx, prop)),
+                _ => None,
+            })
+            .collect();
+        self.row_numbers = tgt_cols
+            .iter()
+            .any(|c| c.virtual_col.is_some_and(|v| v.numbers_rows()));
+        let by_position = match (self.fill_merged, self.cell_data.is_empty()) {
+            (true, _) => Some("fill_merged"),
+            (false, false) => Some("cell_data"),
+            (false, true) if self.row_numbers => Some("_row_number"),
+            (false, true) => None,
+        };
+        if let Some(option) = by_position {
+            if self.tq.is_some()
+                || self.watermark.0.is_some()
+                || self.filter_view
+                || self.csv_fallback
+                || has_expr
+            {
+                return Err(format!(
+                    "{} cannot be used with tq, watermark_column, filter_view, csv_fallback \
+                     or expr",
+                    option
+                ));
+            }
+        }
+        // expression columns have no header labels
+        if self.expected_headers.is_some() && has_expr {
+            return Err("expected_headers cannot be used with expr".to_owned());
+        }
+        self.condition = match (&self.tq, &self.watermark) {
+            (Some(_), (col, _))
+                if col.is_some()
+                    || self.fetch_size.is_some()
+                    || self.max_rows.is_some()
+                    || self.offset_rows > 0
+                    || has_expr =>
+            {
+                return Err(
+                    "tq cannot be used with watermark_column, fetch_size, max_rows, \
+                     offset_rows or expr"
+                        .to_owned(),
+                );
+            }
+            (Some(_), _) => return Ok(()),
+            (None, (Some(col), Some(value))) => {
+                let tgt_col = tgt_cols
+                    .iter()
+                    .find(|c| &c.name == col && c.virtual_col.is_none())
+                    .ok_or(format!("watermark_column {} is not a table column", col))?;
+                Some(format!(
+                    "{} > {}",
+                    column_letters(tgt_col.src_idx as i64),
+                    gviz_literal(tgt_col.type_oid, value)?
+                ))
+            }
+            (None, (None, None)) => None,
+            _ => return Err("watermark_column and watermark_value must be set together".to_owned()),
+        };
+
+        self.select = select_clause(tgt_cols);
+
+        // each chunk selects its columns, under the same conditions so the
+        // rows of all chunks line up
+        if let Some(size) = self.column_chunk_size {
+            let mut positions: Vec<usize> = tgt_cols
+                .iter()
+                .filter(|c| c.virtual_col.is_none())
+                .map(|c| c.src_idx)
+                .collect();
+            // the columns of expected_headers are fetched to check their labels
+            let headers = self.expected_headers.as_ref().map_or(0, Vec::len);
+            positions.extend(0..headers);
+            positions.sort_unstable();
+            positions.dedup();
+            self.column_chunks = positions
+                .chunks(size)
+                .map(|positions| {
+                    let cols: Vec<String> = positions
+                        .iter()
+                        .map(|&idx| column_letters(idx as i64))
+                        .collect();
+                    ColumnChunk {
+                        positions: positions.to_vec(),
+                        select: format!("select {}", cols.join(", ")),
+                    }
+                })
+                .collect();
+        }
+        Ok(())
+    }
+
+    fn build_request(&mut self, url: &str, fetched: usize) -> http::Request {
+        self.start_page(fetched);
+        self.request(url, self.select.as_deref())
+    }
+
+    fn set_filter(&mut self, filter: Option<String>) {
+        self.filter = filter;
+    }
+
+    fn reads_grid(&self) -> bool {
+        self.fill_merged || !self.cell_data.is_empty()
+    }
+
+    fn numbers_rows(&self) -> bool {
+        self.row_numbers
+    }
+
+    fn set_grid(&mut self, grid: Option<SheetGrid>) {
+        self.grid = grid;
+        self.header_rows = 0;
+        self.merged.clear();
+    }
+
+    fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    fn parse_rows(
+        &mut self,
+        resp: &http::Response,
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        check_status(resp)?;
+        let resp = parse_response(&resp.body)?;
+        self.read_page(resp, tgt_cols)
+    }
+
+    fn fetch(
+        &mut self,
+        url: &str,
+        fetched: usize,
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        let mut rows = self.fetch_page(url, fetched, tgt_cols)?;
+        self.set_row_numbers(&mut rows);
+        self.fill_merged_cells(&mut rows, tgt_cols);
+        if let Some(url) = self.cell_data_url(rows.len()) {
+            let data = fetch_spreadsheet(&url)?
+                .sheets
+                .into_iter()
+                .flat_map(|sheet| sheet.data)
+                .next()
+                .unwrap_or_default();
+            self.set_cell_data(&mut rows, &data, tgt_cols.len());
+        }
+        Ok(rows)
+    }
+
+    fn fetch_size(&self) -> Option<usize> {
+        self.fetch_size
+    }
+
+    fn is_last_chunk(&self, rows: usize) -> bool {
+        let reached_max = self.max_rows.is_some_and(|max| self.fetched + rows >= max);
+        reached_max || (!self.truncated && self.page_limit().is_none_or(|limit| rows < limit))
+    }
+}
+
+// check the HTTP status of a gviz response before its body, which is an HTML
+// error page if the request failed
+pub fn check_status(resp: &http::Response) -> FdwResult {
+    match resp.status_code {
+        200..=299 => Ok(()),
+        404 => {
+            Err("spreadsheet or tab is not found (HTTP 404), check its id and sheet_id".to_owned())
+        }
+        401 | 403 => Err(format!(
+            "cannot access the spreadsheet (HTTP {}), share it with anyone with the link or \
+             publish it to the web",
+            resp.status_code
+        )),
+        code => Err(format!("gviz request failed with HTTP {}", code)),
+    }
+}
+
+// parse a gviz response body, which is either JSON behind the ")]}'"
+// anti-hijacking prefix or, if the tqx parameter was ignored, the JSONP
+// "google.visualization.Query.setResponse(...);" call
+pub fn parse_response(body: &str) -> Result<GvizResponse, FdwError> {
+    // a spreadsheet not shared publicly is answered with the HTML sign-in
+    // page instead of the data
+    if body.trim_start().starts_with('<') {
+        return Err(
+            "cannot access the spreadsheet, got a sign-in page instead of its \
+             data, share it with anyone with the link or publish it to the web"
+                .to_owned(),
+        );
+    }
+
+    // remove invalid prefix from response to make a valid JSON string
+    let json = match body.strip_prefix(")]}'") {
+        Some(json) => json,
+        None => body
+            .trim_start_matches("/*O_o*/")
+            .trim_start()
+            .strip_prefix("google.visualization.Query.setResponse(")
+            .and_then(|json| json.trim_end().strip_suffix(");"))
+            .ok_or("invalid response")?,
+    };
+    serde_json::from_str(json).map_err(|e| e.to_string())
+}
+
+// parse the CSV output of a gviz query into a response, its first record has
+// the header labels, CSV has no cell types so the fields are typed by the
+// target column types of the result columns: numbers are parsed from their
+// formatted text like cells without raw value, booleans from TRUE and FALSE
+// and others are text, e.g. dates are parsed by date_format
+#[cfg(feature = "csv")]
+pub fn parse_csv_response(body: &str, types: &[Option<TypeOid>]) -> Result<GvizResponse, FdwError> {
+    let mut records = parse_csv_rows(body, &CsvFormat::default())?.into_iter();
+    let cols = records
+        .next()
+        .map(|row| {
+            row.c
+                .into_iter()
+                .map(|cell| GvizColumn {
+                    label: cell
+                        .map(|cell| cell_text(&cell).into_owned())
+                        .unwrap_or_default(),
+                    kind: String::default(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let rows = records
+        .map(|row| GvizRow {
+            c: row
+                .c
+                .into_iter()
+                .enumerate()
+                .map(|(idx, cell)| {
+                    let text = cell.map(|cell| cell_text(&cell).into_owned())?;
+                    csv_cell(text, types.get(idx).copied().flatten())
+                })
+                .collect(),
+            ..Default::default()
+        })
+        .collect();
+    Ok(GvizResponse {
+        status: "ok".to_owned(),
+        // CSV doesn't tell the header rows, it is not read by sheet position
+        table: Some(GvizTable {
+            cols,
+            rows,
+            parsed_num_headers: 0,
+        }),
+        warnings: Vec::new(),
+        errors: Vec::new(),
+    })
+}
+
+// cell of a CSV field for a target column type, None if the field is empty
+#[cfg(feature = "csv")]
+fn csv_cell(text: String, type_oid: Option<TypeOid>) -> Option<GvizCell> {
+    if text.is_empty() {
+        return None;
+    }
+    let v = match type_oid {
+        Some(type_oid) if is_numeric_type(type_oid) => JsonValue::Null,
+        Some(TypeOid::Bool) if text.eq_ignore_ascii_case("true") => JsonValue::Bool(true),
+        Some(TypeOid::Bool) if text.eq_ignore_ascii_case("false") => JsonValue::Bool(false),
+        _ => {
+            return Some(GvizCell {
+                v: JsonValue::String(text),
+                f: None,
+            })
+        }
+    };
+    Some(GvizCell { v, f: Some(text) })
+}
@@ -1,117 +1,360 @@
+mod api;
+mod auth;
 #[allow(warnings)]
 mod bindings;
-use serde_json::Value as JsonValue;
+mod breaker;
+mod column;
+#[cfg(feature = "csv")]
+mod csv;
+mod datetime;
+mod failover;
+mod gviz;
+mod metrics;
+mod modify;
+mod object;
+mod options;
+mod ratelimit;
+mod reshape;
+#[cfg(feature = "rest")]
+mod rest;
+mod retry;
+mod source;
+#[cfg(test)]
+mod tests;
+mod timing;
 
+use std::borrow::Cow;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard};
+
+use api::{fetch_spreadsheet, load_api_keys, reset_api_keys, SheetGrid, Spreadsheet};
 use bindings::{
     exports::supabase::wrappers::routines::Guest,
     supabase::wrappers::{
-        http, time,
-        types::{Cell, Context, FdwError, FdwResult, OptionsType, Row, TypeOid},
+        time,
+        types::{Cell, Context, FdwError, FdwResult, Options, OptionsType, Row},
         utils,
     },
 };
+use column::{
+    cell_text, display_text, resolve_cell_of, row_hash, row_version, version_positions, ColumnSpec,
+    VirtualColumn,
+};
+use gviz::{a1_start, encode_query, GvizRow};
+use metrics::Counter;
+use modify::ModifyState;
+use object::Object;
+use options::{get_bool_option, get_spread_sheet_ids, parse_list_option, WithDefaults};
+use reshape::Reshape;
+use source::{source_from_options, Source};
 
+// max number of rows cached for a re_scan if fetch_size is not set
+const DEFAULT_RESCAN_CACHE_ROWS: usize = 1000;
+
+// state of a single foreign table scan, rebuilt by every begin_scan
 #[derive(Debug, Default)]
-struct ExampleFdw {
-    base_url: String,
-    src_rows: Vec<JsonValue>,
+struct ScanState {
+    // backend the rows are read from, set by the source server option
+    source: Box<dyn Source>,
+    // spreadsheets scanned one after another, their rows are concatenated
+    sources: Vec<ScanSource>,
+    // index of the spreadsheet being scanned
+    source_idx: usize,
+    // spreadsheet object scanned instead of the sheet data
+    object: Option<Object>,
+    tgt_cols: Vec<ColumnSpec>,
+    // stop at the first row whose mapped sheet cells are all empty
+    stop_at_empty_row: bool,
+    // skip rows whose mapped sheet cells are all empty
+    skip_blank_rows: bool,
+    // each spreadsheet is reshaped into records, e.g. a key/value sheet
+    reshape: Option<Reshape>,
+    // rows too short for the mapped columns are an error rather than padded
+    // with NULLs, cells given as null are not missing
+    strict_ragged_rows: bool,
+    // max number of cells failed to convert and set to NULL, any conversion
+    // failure is an error if not set
+    max_cell_errors: Option<usize>,
+    // number of cells failed to convert so far
+    cell_errors: usize,
+    // cells converted to NULL by lenient coercion, by target column
+    coerced_nulls: Vec<CoercedNulls>,
+    // rows returned so far, replayed by a re_scan in the same scan, None if
+    // they are downloaded again instead, as refetch_on_rescan asks or once
+    // there are more of them than rescan_cache_rows
+    cached_rows: Option<Vec<Vec<Option<Cell>>>>,
+    // max number of rows cached for a re_scan, fetch_size if set so the cache
+    // is never larger than the row buffer
+    rescan_cache_rows: usize,
+    // position of the next replayed row after a re_scan
+    replay_idx: Option<usize>,
+    // number of source rows fetched so far from the current spreadsheet
+    fetched: usize,
+    // there are no more source rows left to fetch from the current spreadsheet
+    exhausted: bool,
+    src_rows: Vec<GvizRow>,
     src_idx: usize,
+    // column of soft_delete_column, rows with a cell in it are deleted and
+    // left out
+    soft_delete: Option<String>,
 }
 
-// pointer for the static FDW instance
-static mut INSTANCE: *mut ExampleFdw = std::ptr::null_mut::<ExampleFdw>();
+// cells of a column which lenient coercion could not convert and set to NULL,
+// summarized in a single warning at the end of the scan
+#[derive(Debug, Default)]
+struct CoercedNulls {
+    count: usize,
+    // the first of the cells, by row number and text
+    row_num: usize,
+    text: String,
+}
 
-impl ExampleFdw {
-    // initialise FDW instance
-    fn init_instance() {
-        let instance = Self::default();
-        unsafe {
-            INSTANCE = Box::leak(Box::new(instance));
+impl CoercedNulls {
+    // count a cell converted to NULL
+    fn add(&mut self, row_num: usize, text: &str) {
+        if self.count == 0 {
+            self.row_num = row_num;
+            self.text = text.to_owned();
         }
+        self.count += 1;
     }
 
-    fn this_mut() -> &'static mut Self {
-        unsafe { &mut (*INSTANCE) }
+    // the warning about the cells of a column, None if there are none
+    fn warning(&self, column: &str) -> Option<String> {
+        (self.count > 0).then(|| {
+            format!(
+                "column {}: {} cells could not be converted, e.g. row {}: '{}'",
+                column, self.count, self.row_num, self.text
+            )
+        })
     }
 }
 
-impl Guest for ExampleFdw {
-    fn host_version_requirement() -> String {
-        // semver expression for Wasm FDW host version requirement
-        // ref: https://docs.rs/semver/latest/semver/enum.Op.html
-        "^0.1.0".to_string()
+// a spreadsheet of a scan
+#[derive(Debug, Default)]
+pub struct ScanSource {
+    spread_sheet_id: String,
+    // tab given by the sheet_id or named_range option, e.g. "gid 0", named in
+    // errors
+    sheet: Option<String>,
+    // gviz request URL without the tq query, or the object request URL
+    url: String,
+    // gviz condition of the filter_view option on the rows
+    filter: Option<String>,
+    // tab and range read in the sheet grid, if cells are read by position
+    grid: Option<SheetGrid>,
+}
+
+impl ScanSource {
+    // prefix an error with the spreadsheet and tab it is about
+    fn error(&self, e: FdwError) -> FdwError {
+        match (self.spread_sheet_id.is_empty(), &self.sheet) {
+            (true, _) => e,
+            (false, Some(sheet)) => {
+                format!("spreadsheet {} {}: {}", self.spread_sheet_id, sheet, e)
+            }
+            (false, None) => format!("spreadsheet {}: {}", self.spread_sheet_id, e),
+        }
     }
+}
 
-    fn init(ctx: &Context) -> FdwResult {
-        Self::init_instance();
-        let this = Self::this_mut();
+impl ScanState {
+    // replace the drained row buffer with the next chunk of source rows
+    fn fetch_next(&mut self) -> FdwResult {
+        if let Some(object) = self.object {
+            return self.load_object(object);
+        }
+        // errors name the spreadsheet failed among the ones scanned
+        let source = &self.sources[self.source_idx];
+        if self.fetched == 0 {
+            self.source.set_filter(source.filter.clone());
+            self.source.set_grid(source.grid.clone());
+        }
+        self.src_rows = self
+            .source
+            .fetch(&source.url, self.fetched, &self.tgt_cols)
+            .map_err(|e| source.error(e))?;
+        self.src_idx = 0;
+        self.fetched += self.src_rows.len();
+        self.exhausted = self.source.is_last_chunk(self.src_rows.len());
 
-        // get API URL from foreign server options if it is specified
-        let opts = ctx.get_options(OptionsType::Server);
-        this.base_url = opts.require_or("base_url", "https://docs.google.com/spreadsheets/d");
+        // a sheet whose rows are not records is reshaped once all of its rows
+        // are fetched
+        if let Some(reshape) = &self.reshape {
+            while !self.exhausted {
+                let rows = self
+                    .source
+                    .fetch(&source.url, self.fetched, &self.tgt_cols)
+                    .map_err(|e| source.error(e))?;
+                self.fetched += rows.len();
+                self.exhausted = self.source.is_last_chunk(rows.len());
+                self.src_rows.extend(rows);
+            }
+            let rows = std::mem::take(&mut self.src_rows);
+            self.src_rows = reshape
+                .apply(rows, &self.tgt_cols, self.source.labels())
+                .map_err(|e| source.error(e))?;
+            self.fetched = self.src_rows.len();
+        }
+        Ok(())
+    }
 
+    // load all rows of a spreadsheet object at once from its metadata
+    fn load_object(&mut self, object: Object) -> FdwResult {
+        let source = &self.sources[self.source_idx];
+        self.src_rows = object
+            .load_rows(&source.url, &self.tgt_cols)
+            .map_err(|e| source.error(e))?;
+        self.src_idx = 0;
+        self.fetched = self.src_rows.len();
+        self.exhausted = true;
         Ok(())
     }
 
-    fn begin_scan(ctx: &Context) -> FdwResult {
-        let this = Self::this_mut();
+    // move on to the next spreadsheet, false if all of them are scanned
+    fn next_source(&mut self) -> bool {
+        if self.source_idx + 1 >= self.sources.len() {
+            return false;
+        }
+        self.source_idx += 1;
+        self.fetched = 0;
+        self.exhausted = false;
+        self.src_rows.clear();
+        self.src_idx = 0;
+        true
+    }
 
-        // get sheet id from foreign table options and make the request URL
-        let opts = ctx.get_options(OptionsType::Table);
-        let spread_sheet_id = opts.require("spread_sheet_id")?;
-        let sheet_id = opts.get("sheet_id");
-        let url = format!("{}/{}/gviz/tq?tqx=out:json", this.base_url, spread_sheet_id,);
+    // skip the remaining rows of the current spreadsheet
+    fn end_source(&mut self) {
+        self.exhausted = true;
+        self.src_idx = self.src_rows.len();
+    }
 
-        let url = match sheet_id {
-            Some(sheet_id) => format!(
-                "{}/{}/gviz/tq?gid={}&tqx=out:json",
-                this.base_url, spread_sheet_id, sheet_id,
-            ),
-            None => format!("{}/{}/gviz/tq?tqx=out:json", this.base_url, spread_sheet_id,),
-        };
+    // get the next row of the scan, None if there are no more rows
+    fn next_row(&mut self) -> Result<Option<Vec<Option<Cell>>>, FdwError> {
+        // rows returned before a re_scan are replayed first
+        if let Some(idx) = self.replay_idx {
+            if let Some(cells) = self.cached_rows.as_ref().and_then(|rows| rows.get(idx)) {
+                self.replay_idx = Some(idx + 1);
+                return Ok(Some(cells.clone()));
+            }
+            self.replay_idx = None;
+        }
 
-        // make up request headers
-        let headers: Vec<(String, String)> = vec![
-            ("user-agent".to_owned(), "Sheets FDW".to_owned()),
-            // header to make JSON response more cleaner
-            ("x-datasource-auth".to_owned(), "true".to_owned()),
-        ];
-
-        // make a request to Google API and parse response as JSON
-        let req = http::Request {
-            method: http::Method::Get,
-            url,
-            headers,
-            body: String::default(),
-        };
-        let resp = http::get(&req)?;
-        // remove invalid prefix from response to make a valid JSON string
-        let body = resp.body.strip_prefix(")]}'\n").ok_or("invalid response")?;
-        let resp_json: JsonValue = serde_json::from_str(body).map_err(|e| e.to_string())?;
+        let cells = loop {
+            // if buffered source rows are consumed, fetch the next chunk or
+            // move on to the next spreadsheet, stop data scan if there are no
+            // more rows
+            if self.src_idx >= self.src_rows.len() {
+                if self.exhausted && !self.next_source() {
+                    return Ok(None);
+                }
+                self.fetch_next()?;
+                continue;
+            }
 
-        // extract source rows from response
-        this.src_rows = resp_json
-            .pointer("/table/rows")
-            .ok_or("cannot get rows from response")
-            .map(|v| v.as_array().unwrap().to_owned())?;
+            // rows marked deleted are skipped before they are converted
+            if self.is_soft_deleted()? {
+                self.src_idx += 1;
+                continue;
+            }
 
-        // output a Postgres INFO to user (visible in psql), also useful for debugging
-        utils::report_info(&format!(
-            "We got response array length: {}",
-            this.src_rows.len()
-        ));
+            // convert current source row to target cells
+            let (cells, blank) = self.take_row()?;
+            if !blank {
+                break cells;
+            }
 
-        Ok(())
+            // trailing rows below the real data are often empty but still part
+            // of the sheet grid, stop scanning the spreadsheet at the first one
+            // if asked
+            if self.stop_at_empty_row {
+                self.end_source();
+                continue;
+            }
+
+            // blank rows used as separators inside the data are skipped if
+            // asked, instead of being returned as all-NULL rows
+            if !self.skip_blank_rows {
+                break cells;
+            }
+        };
+
+        // a cache past its cap is dropped rather than growing with the sheet
+        if let Some(rows) = &mut self.cached_rows {
+            match rows.len() < self.rescan_cache_rows {
+                true => rows.push(cells.clone()),
+                false => self.cached_rows = None,
+            }
+        }
+        Ok(Some(cells))
     }
 
-    fn iter_scan(ctx: &Context, row: &Row) -> Result<Option<u32>, FdwError> {
-        let this = Self::this_mut();
+    // the current source row has a cell in soft_delete_column, found among
+    // the columns read or else by its header label
+    fn is_soft_deleted(&self) -> Result<bool, FdwError> {
+        let Some(name) = &self.soft_delete else {
+            return Ok(false);
+        };
+        let pos = match self
+            .tgt_cols
+            .iter()
+            .find(|c| &c.name == name && c.virtual_col.is_none() && c.expr.is_none())
+        {
+            Some(tgt_col) => tgt_col.src_idx,
+            None => self
+                .source
+                .labels()
+                .iter()
+                .position(|label| label == name)
+                .ok_or(format!(
+                    "soft_delete_column {} is neither read nor a header label of the sheet",
+                    name
+                ))?,
+        };
+        Ok(self.src_rows[self.src_idx]
+            .c
+            .get(pos)
+            .and_then(Option::as_ref)
+            .is_some_and(|cell| !display_text(cell).is_empty()))
+    }
 
-        // if all source rows are consumed, stop data scan
-        if this.src_idx >= this.src_rows.len() {
-            return Ok(None);
+    // restart the scan from its first row, by replaying the rows returned so
+    // far or by fetching them again if they are not cached
+    fn rescan(&mut self) -> FdwResult {
+        if self.cached_rows.is_some() {
+            self.replay_idx = Some(0);
+            return Ok(());
         }
+        self.replay_idx = None;
+        self.source_idx = 0;
+        self.fetched = 0;
+        self.exhausted = false;
+        self.src_rows.clear();
+        self.src_idx = 0;
+        self.cell_errors = 0;
+        self.coerced_nulls.clear();
+        self.fetch_next()
+    }
+
+    // warnings about the cells lenient coercion set to NULL, one per column
+    fn coercion_warnings(&self) -> Vec<String> {
+        self.tgt_cols
+            .iter()
+            .zip(&self.coerced_nulls)
+            .filter_map(|(tgt_col, nulls)| nulls.warning(&tgt_col.name))
+            .collect()
+    }
 
+    // 1-based position of the current source row in the scan
+    fn row_num(&self) -> usize {
+        self.fetched - self.src_rows.len() + self.src_idx + 1
+    }
+
+    // convert the current source row to target cells and advance to the next
+    // source row, with whether the row is blank: its sheet cells are all
+    // empty, before defaults and regardless of virtual columns
+    fn take_row(&mut self) -> Result<(Vec<Option<Cell>>, bool), FdwError> {
         // extract current source row, an example of the source row in JSON:
         // {
         //   "c": [{
@@ -122,67 +365,695 @@ impl Guest for ExampleFdw {
         //    }, null, null, null, null, { "v": null }
         //    ]
         // }
-        let src_row = &this.src_rows[this.src_idx];
+        // each row is visited only once, so its cell values are moved out
+        // instead of cloned
+        let row_num = self.row_num();
+        let src_row = &mut self.src_rows[self.src_idx];
+        let source = &self.sources[self.source_idx];
+
+        // the row version covers the displayed cells, taken before the
+        // conversion moves the cell values out
+        let version = match self
+            .tgt_cols
+            .iter()
+            .any(|c| c.virtual_col == Some(VirtualColumn::RowVersion))
+        {
+            true => src_row.row_number.map(|row| {
+                let texts: Vec<Cow<str>> = version_positions(&self.tgt_cols)
+                    .into_iter()
+                    .map(|pos| match src_row.c.get(pos) {
+                        Some(Some(src)) => display_text(src),
+                        _ => Cow::Borrowed(""),
+                    })
+                    .collect();
+                let texts: Vec<&str> = texts.iter().map(AsRef::as_ref).collect();
+                row_version(row, &texts)
+            }),
+            false => None,
+        };
 
         // loop through each target column, map source cell to target cell
-        for tgt_col in ctx.get_columns() {
-            let (tgt_col_num, tgt_col_name) = (tgt_col.num(), tgt_col.name());
-            if let Some(src) = src_row.pointer(&format!("/c/{}/v", tgt_col_num - 1)) {
-                // we only support I64 and String cell types here, add more type
-                // conversions if you need
-                let cell = match tgt_col.type_oid() {
-                    TypeOid::I64 => src.as_f64().map(|v| Cell::I64(v as _)),
-                    TypeOid::String => src.as_str().map(|v| Cell::String(v.to_owned())),
-                    _ => {
-                        return Err(format!(
-                            "column {} data type is not supported",
-                            tgt_col_name
-                        ));
+        let mut cells = Vec::with_capacity(self.tgt_cols.len());
+        let mut blank = true;
+        self.coerced_nulls
+            .resize_with(self.tgt_cols.len(), Default::default);
+        for (idx, tgt_col) in self.tgt_cols.iter().enumerate() {
+            if let Some(virtual_col) = tgt_col.virtual_col {
+                let cell = match virtual_col {
+                    VirtualColumn::CellData(_) => {
+                        src_row.cell_data.get_mut(idx).and_then(Option::take)
                     }
+                    VirtualColumn::RowNumber => src_row.row_number.map(|n| Cell::I64(n as i64)),
+                    VirtualColumn::RowVersion => version.clone().map(Cell::String),
+                    _ => virtual_col.cell(source),
                 };
+                cells.push(cell);
+                continue;
+            }
 
-                // push the cell to target row
-                row.push(cell.as_ref());
-            } else {
-                row.push(None);
+            if self.strict_ragged_rows && tgt_col.src_idx >= src_row.c.len() {
+                return Err(format!(
+                    "row {}: has {} cells, column {} is missing",
+                    row_num,
+                    src_row.c.len(),
+                    tgt_col.name
+                ));
             }
+
+            let cell = match src_row.c.get_mut(tgt_col.src_idx) {
+                Some(Some(src)) => {
+                    // taken before the conversion moves the cell value out
+                    blank &= tgt_col.is_empty(src);
+                    let coerced = tgt_col
+                        .coerces_to_null(src)
+                        .then(|| tgt_col.redact(&cell_text(src)).into_owned());
+                    match self.source.convert_cell(tgt_col, src) {
+                        Ok(None) if coerced.is_some() => {
+                            self.coerced_nulls[idx].add(row_num, &coerced.unwrap_or_default());
+                            None
+                        }
+                        Ok(cell) => cell,
+                        // tolerate conversion failures up to max_cell_errors
+                        Err(e) => match self.max_cell_errors {
+                            Some(max) if self.cell_errors < max => {
+                                self.cell_errors += 1;
+                                None
+                            }
+                            Some(max) => {
+                                return Err(format!(
+                                    "row {}: {}, exceeded max_cell_errors {}",
+                                    row_num, e, max
+                                ))
+                            }
+                            None => return Err(format!("row {}: {}", row_num, e)),
+                        },
+                    }
+                }
+                _ => tgt_col.default.clone(),
+            };
+            cells.push(cell);
+        }
+
+        // the row hash covers the converted cells
+        if let Some(idx) = self
+            .tgt_cols
+            .iter()
+            .position(|c| c.virtual_col == Some(VirtualColumn::RowHash))
+        {
+            cells[idx] = Some(Cell::String(row_hash(&self.tgt_cols, &cells)));
         }
 
         // advance to next source row
-        this.src_idx += 1;
+        self.src_idx += 1;
+
+        Ok((cells, blank))
+    }
+}
+
+#[derive(Debug, Default)]
+struct ExampleFdw {
+    // gviz base URL, the primary of the base_url list
+    base_url: String,
+    // Sheets v4 API URL, used for spreadsheet metadata
+    api_url: String,
+    // Drive v3 API URL of file list, used for folder listing
+    drive_api_url: String,
+    scan: ScanState,
+    modify: ModifyState,
+}
+
+// the static FDW instance, the Wrappers host instantiates the component for
+// each foreign scan, so scans in the same query (self-joins, joins between
+// sheets) never share this instance
+static INSTANCE: Mutex<Option<ExampleFdw>> = Mutex::new(None);
+
+// exclusive access to the initialised FDW instance
+struct InstanceGuard(MutexGuard<'static, Option<ExampleFdw>>);
+
+impl Deref for InstanceGuard {
+    type Target = ExampleFdw;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("FDW instance is not initialised")
+    }
+}
+
+impl DerefMut for InstanceGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().expect("FDW instance is not initialised")
+    }
+}
+
+impl ExampleFdw {
+    fn lock() -> MutexGuard<'static, Option<ExampleFdw>> {
+        // a panic aborts the Wasm instance, so a poisoned lock can be reused
+        INSTANCE.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // initialise FDW instance, dropping any state from a previous init
+    fn init_instance() {
+        *Self::lock() = Some(Self::default());
+    }
+
+    fn this_mut() -> InstanceGuard {
+        InstanceGuard(Self::lock())
+    }
+
+    // make the request URL of a scan from foreign table options, with the row
+    // filter of the filter_view option and, if asked, the tab and range read
+    // in the sheet grid, or only the top-left cell of the range if the rows
+    // are just numbered
+    fn scan_url(
+        &self,
+        object: Option<Object>,
+        spread_sheet_id: &str,
+        opts: &Options,
+        server_opts: &Options,
+        (reads_grid, numbers_rows): (bool, bool),
+    ) -> Result<(String, Option<String>, Option<SheetGrid>), FdwError> {
+        // spreadsheet objects are read from the metadata rather than gviz
+        if let Some(object) = object {
+            let url = self.metadata_url(spread_sheet_id, object.fields(), server_opts)?;
+            return Ok((url, None, None));
+        }
+        let grid_fields = "sheets(properties(sheetId,title,gridProperties),merges)";
+        let mut grid = None;
+
+        // a named range is resolved to its tab and A1 range from the metadata,
+        // a filter view to its tab and the condition of its criteria
+        let mut filter = None;
+        let sheet_name = opts.get("sheet_name");
+        if sheet_name.is_some() && opts.get("sheet_id").is_some() {
+            return Err("sheet_name cannot be used with sheet_id".to_owned());
+        }
+        let has_sheet =
+            opts.get("sheet_id").is_some() || sheet_name.is_some() || opts.get("range").is_some();
+        let (sheet_id, range) = match (opts.get("named_range"), opts.get("filter_view")) {
+            (Some(_), Some(_)) => {
+                return Err("named_range cannot be used with filter_view".to_owned());
+            }
+            (Some(_), None) if has_sheet => {
+                return Err(
+                    "named_range cannot be used with sheet_id, sheet_name or range".to_owned(),
+                );
+            }
+            (None, Some(_)) if has_sheet => {
+                return Err(
+                    "filter_view cannot be used with sheet_id, sheet_name or range".to_owned(),
+                );
+            }
+            (None, Some(name)) => {
+                let url = self.metadata_url(
+                    spread_sheet_id,
+                    "sheets.filterViews(filterViewId,title,range,filterSpecs,criteria)",
+                    server_opts,
+                )?;
+                let (sheet_id, condition) = fetch_spreadsheet(&url)?.resolve_filter_view(&name)?;
+                filter = condition;
+                (Some(sheet_id), None)
+            }
+            (Some(name), None) => {
+                let fields = match reads_grid {
+                    true => format!("namedRanges,{}", grid_fields),
+                    false => "namedRanges,sheets.properties(sheetId,gridProperties)".to_owned(),
+                };
+                let meta = fetch_spreadsheet(&self.metadata_url(
+                    spread_sheet_id,
+                    &fields,
+                    server_opts,
+                )?)?;
+                let (sheet_id, range) = meta.resolve_named_range(&name)?;
+                if reads_grid {
+                    grid = Some(meta.sheet_grid(sheet_id.as_deref(), range.as_deref())?);
+                }
+                (sheet_id, range)
+            }
+            (None, None) => {
+                let (sheet_id, range) = (opts.get("sheet_id"), opts.get("range"));
+                if reads_grid {
+                    let url = self.metadata_url(spread_sheet_id, grid_fields, server_opts)?;
+                    let meta = fetch_spreadsheet(&url)?;
+                    let sheet_id = match &sheet_name {
+                        Some(name) => Some(meta.sheet_id_of(name)?),
+                        None => sheet_id.clone(),
+                    };
+                    grid = Some(meta.sheet_grid(sheet_id.as_deref(), range.as_deref())?);
+                }
+                (sheet_id, range)
+            }
+        };
+        if grid.is_none() && numbers_rows {
+            let (row, col) = range.as_deref().map_or((0, 0), a1_start);
+            grid = Some(SheetGrid {
+                row,
+                col,
+                ..Default::default()
+            });
+        }
+        if let Some(grid) = &mut grid {
+            grid.url = format!("{}/{}", self.api_url, spread_sheet_id);
+        }
+
+        // gviz finds a tab by gid or by title
+        let url = match (sheet_id, sheet_name) {
+            (Some(sheet_id), _) => format!(
+                "{}/{}/gviz/tq?gid={}&tqx=out:json",
+                self.base_url, spread_sheet_id, sheet_id,
+            ),
+            (None, Some(name)) => format!(
+                "{}/{}/gviz/tq?sheet={}&tqx=out:json",
+                self.base_url,
+                spread_sheet_id,
+                encode_query(&name),
+            ),
+            (None, None) => format!("{}/{}/gviz/tq?tqx=out:json", self.base_url, spread_sheet_id,),
+        };
+        let url = match range {
+            Some(range) => format!("{}&range={}", url, encode_query(&range)),
+            None => url,
+        };
+        Ok((url, filter, grid))
+    }
+
+    // make Drive API request URL to list the spreadsheets in a folder
+    fn drive_folder_url(&self, folder_id: &str, server_opts: &Options) -> Result<String, FdwError> {
+        load_api_keys(server_opts)?;
+        let query = format!(
+            "'{}' in parents and mimeType = 'application/vnd.google-apps.spreadsheet' \
+             and trashed = false",
+            folder_id.replace('\\', "\\\\").replace('\'', "\\'")
+        );
+        Ok(format!(
+            "{}?q={}&fields={}&pageSize=1000&supportsAllDrives=true\
+             &includeItemsFromAllDrives=true",
+            self.drive_api_url,
+            encode_query(&query),
+            encode_query(Object::DriveFolder.fields()),
+        ))
+    }
+
+    // make Sheets v4 API request URL for the spreadsheet metadata fields
+    fn metadata_url(
+        &self,
+        spread_sheet_id: &str,
+        fields: &str,
+        server_opts: &Options,
+    ) -> Result<String, FdwError> {
+        load_api_keys(server_opts)?;
+        Ok(format!(
+            "{}/{}?fields={}",
+            self.api_url,
+            spread_sheet_id,
+            encode_query(fields),
+        ))
+    }
+
+    // set up a scan from the table options and fetch its first rows
+    fn start_scan(ctx: &Context) -> FdwResult {
+        let mut this = Self::this_mut();
+
+        // get sheet id from foreign table options and make the request URL
+        let opts = ctx.get_options(OptionsType::Table);
+        let server_opts = ctx.get_options(OptionsType::Server);
+        ratelimit::configure(&server_opts)?;
+        retry::configure(&server_opts)?;
+        breaker::configure(&server_opts)?;
+        timing::configure(get_bool_option(&opts, "report_timing")?);
+        reset_api_keys();
+        metrics::add(Counter::Scans, 1);
+        let mut source = source_from_options(&server_opts, &opts)?;
+        let object = match source.reads_spreadsheets() {
+            true => Object::parse(opts.get("object").as_deref())?,
+            false => None,
+        };
+
+        // trailing cells omitted from short rows are NULL unless asked
+        let strict_ragged_rows = match opts.get("ragged_rows").as_deref() {
+            None | Some("pad") => false,
+            Some("error") => true,
+            Some(v) => {
+                return Err(format!(
+                    "invalid ragged_rows '{}', expect 'pad' or 'error'",
+                    v
+                ))
+            }
+        };
+
+        // sheets whose rows are not records, e.g. a key/value sheet with keys
+        // in its first column and values in its second
+        let reshape = Reshape::from_options(&opts)?;
+        if let Some(reshape) = &reshape {
+            if object.is_some() {
+                return Err(format!("{} cannot be used with object", reshape.option()));
+            }
+            if server_opts.get("source").as_deref() == Some("rest") {
+                return Err(format!(
+                    "{} cannot be used with source 'rest'",
+                    reshape.option()
+                ));
+            }
+        }
+
+        // number of bad cells tolerated before the scan is aborted
+        let max_cell_errors = match opts.get("max_cell_errors") {
+            Some(max) => match max.parse::<usize>() {
+                Ok(max) => Some(max),
+                _ => return Err(format!("invalid max_cell_errors '{}'", max)),
+            },
+            None => None,
+        };
+
+        // resolve target columns once rather than on every iter_scan, with
+        // the column defaults of the source below the server options
+        let server_defaults = WithDefaults {
+            opts: &server_opts,
+            defaults: source.column_defaults(),
+        };
+        let mut tgt_cols: Vec<ColumnSpec> = ctx
+            .get_columns()
+            .iter()
+            .map(|tgt_col| ColumnSpec::new(tgt_col, &server_defaults, &opts))
+            .collect::<Result<_, _>>()?;
+
+        resolve_cell_of(&mut tgt_cols)?;
+        if !source.reads_spreadsheets() && tgt_cols.iter().any(|c| c.cell_of.is_some()) {
+            return Err("cell_data columns can only be read from source 'gviz'".to_owned());
+        }
+        let row_numbers = tgt_cols
+            .iter()
+            .any(|c| c.virtual_col.is_some_and(|v| v.numbers_rows()));
+        if row_numbers && (!source.reads_spreadsheets() || object.is_some() || reshape.is_some()) {
+            return Err(
+                "_row_number and _row_version can only be read from the sheet rows of source \
+                 'gviz'"
+                    .to_owned(),
+            );
+        }
+
+        let soft_delete = opts.get("soft_delete_column");
+        if soft_delete.is_some()
+            && (!source.reads_spreadsheets() || object.is_some() || reshape.is_some())
+        {
+            return Err(
+                "soft_delete_column can only be used with the sheet rows of source 'gviz'"
+                    .to_owned(),
+            );
+        }
+
+        // columns of an object scan are its attributes, besides virtual columns
+        if let Some(object) = object {
+            if let Some(tgt_col) = tgt_cols
+                .iter()
+                .find(|c| c.virtual_col.is_none() && !object.columns().contains(&c.name.as_str()))
+            {
+                return Err(format!(
+                    "column {} is not an attribute of object '{}', expected one of {}",
+                    tgt_col.name,
+                    object.name(),
+                    object.columns().join(", ")
+                ));
+            }
+        }
+
+        // the sheet columns are read as is
+        if let Some(reshape) = &reshape {
+            if opts.get("column_chunk_size").is_some()
+                || tgt_cols
+                    .iter()
+                    .any(|c| c.expr.is_some() || c.cell_of.is_some())
+            {
+                return Err(format!(
+                    "{} cannot be used with column_chunk_size, expr or cell_data",
+                    reshape.option()
+                ));
+            }
+        }
+
+        source.prepare(&tgt_cols)?;
+
+        // merges and cell data are read from the sheet grid of each
+        // spreadsheet
+        let reads_grid = source.reads_grid();
+        if reads_grid && object.is_some() {
+            return Err("fill_merged and cell_data cannot be used with object".to_owned());
+        }
+        let numbers_rows = source.numbers_rows();
+        let sources = match object {
+            // other sources than spreadsheets are read from a single URL
+            _ if !source.reads_spreadsheets() => vec![ScanSource {
+                url: opts.require("url")?,
+                ..Default::default()
+            }],
+            // the metrics are kept by the host, not in a spreadsheet
+            Some(Object::Metrics) => vec![ScanSource::default()],
+            // a Drive folder is listed through Drive API, not in a spreadsheet
+            Some(Object::DriveFolder) => vec![ScanSource {
+                url: this.drive_folder_url(&opts.require("folder_id")?, &server_opts)?,
+                ..Default::default()
+            }],
+            _ => get_spread_sheet_ids(&opts)?
+                .into_iter()
+                .map(|id| {
+                    let mut source = ScanSource {
+                        spread_sheet_id: id,
+                        sheet: match (object, opts.get("sheet_id"), opts.get("named_range")) {
+                            (Some(_), _, _) => None,
+                            (None, Some(gid), _) => Some(format!("gid {}", gid)),
+                            (None, _, None) if opts.get("sheet_name").is_some() => {
+                                opts.get("sheet_name").map(|name| format!("tab '{}'", name))
+                            }
+                            (None, _, Some(name)) => Some(format!("named range '{}'", name)),
+                            _ => opts
+                                .get("filter_view")
+                                .map(|name| format!("filter view '{}'", name)),
+                        },
+                        ..Default::default()
+                    };
+                    (source.url, source.filter, source.grid) = this
+                        .scan_url(
+                            object,
+                            &source.spread_sheet_id,
+                            &opts,
+                            &server_opts,
+                            (reads_grid, numbers_rows),
+                        )
+                        .map_err(|e| source.error(e))?;
+                    Ok(source)
+                })
+                .collect::<Result<_, FdwError>>()?,
+        };
+
+        // start a fresh scan and fetch the first chunk of source rows
+        let rescan_cache_rows = source.fetch_size().unwrap_or(DEFAULT_RESCAN_CACHE_ROWS);
+        this.scan = ScanState {
+            source,
+            sources,
+            object,
+            tgt_cols,
+            stop_at_empty_row: get_bool_option(&opts, "stop_at_empty_row")?,
+            skip_blank_rows: get_bool_option(&opts, "skip_blank_rows")?,
+            reshape,
+            cached_rows: match get_bool_option(&opts, "refetch_on_rescan")? {
+                true => None,
+                false => Some(Vec::new()),
+            },
+            rescan_cache_rows,
+            strict_ragged_rows,
+            max_cell_errors,
+            soft_delete,
+            ..Default::default()
+        };
+        this.scan.fetch_next()?;
+
+        // output a Postgres INFO to user (visible in psql), also useful for debugging
+        utils::report_info(&format!(
+            "We got response array length: {}",
+            this.scan.src_rows.len()
+        ));
+
+        Ok(())
+    }
+
+    // set up a modify from the table options, finding the tab written and
+    // the keys of the rows upserted
+    fn start_modify(ctx: &Context) -> FdwResult {
+        let mut this = Self::this_mut();
+
+        let opts = ctx.get_options(OptionsType::Table);
+        let server_opts = ctx.get_options(OptionsType::Server);
+        ratelimit::configure(&server_opts)?;
+        retry::configure(&server_opts)?;
+        breaker::configure(&server_opts)?;
+        auth::configure(&server_opts)?;
+        let source = source_from_options(&server_opts, &opts)?;
+        if !source.reads_spreadsheets()
+            || opts.get("object").is_some()
+            || Reshape::from_options(&opts)?.is_some()
+        {
+            return Err("only the sheet rows of source 'gviz' can be modified".to_owned());
+        }
+        if opts.get("spreadsheet_ids").is_some() {
+            return Err("tables of spreadsheet_ids cannot be modified".to_owned());
+        }
+
+        // fail before the first row if the spreadsheet cannot be written
+        let spreadsheet_id = opts.require("spread_sheet_id")?;
+        modify::preflight(&this.drive_api_url, &spreadsheet_id)?;
+
+        let url = format!("{}/{}", this.api_url, spreadsheet_id);
+        let meta: Spreadsheet =
+            serde_json::from_str(&modify::send(modify::metadata_request(&url))?.body)
+                .map_err(|e| format!("invalid spreadsheet metadata: {}", e))?;
+
+        let server_defaults = WithDefaults {
+            opts: &server_opts,
+            defaults: source.column_defaults(),
+        };
+        let tgt_cols: Vec<ColumnSpec> = ctx
+            .get_columns()
+            .iter()
+            .map(|tgt_col| ColumnSpec::new(tgt_col, &server_defaults, &opts))
+            .collect::<Result<_, _>>()?;
+        this.modify = ModifyState::new(url, &meta, tgt_cols, &opts)?;
+        this.modify.create_sheet()?;
+        this.modify.fetch_keys()
+    }
+}
+
+impl Guest for ExampleFdw {
+    fn host_version_requirement() -> String {
+        // semver expression for Wasm FDW host version requirement
+        // ref: https://docs.rs/semver/latest/semver/enum.Op.html
+        "^0.1.0".to_string()
+    }
+
+    fn init(ctx: &Context) -> FdwResult {
+        Self::init_instance();
+        let mut this = Self::this_mut();
+
+        // get API URL from foreign server options if it is specified
+        let opts = ctx.get_options(OptionsType::Server);
+        // more than one base URL are mirrors failed over to in order
+        let base_urls = parse_list_option(
+            &opts.require_or("base_url", "https://docs.google.com/spreadsheets/d"),
+        );
+        this.base_url = base_urls
+            .first()
+            .cloned()
+            .ok_or("base_url cannot be empty")?;
+        failover::configure(base_urls);
+        this.api_url = opts.require_or("api_url", "https://sheets.googleapis.com/v4/spreadsheets");
+        this.drive_api_url =
+            opts.require_or("drive_api_url", "https://www.googleapis.com/drive/v3/files");
+
+        Ok(())
+    }
+
+    fn begin_scan(ctx: &Context) -> FdwResult {
+        // a failed scan doesn't get to end_scan, its counts are added at once
+        Self::start_scan(ctx).inspect_err(|_| metrics::flush())
+    }
+
+    fn iter_scan(_ctx: &Context, row: &Row) -> Result<Option<u32>, FdwError> {
+        let mut this = Self::this_mut();
+        let Some(cells) = this.scan.next_row().inspect_err(|_| metrics::flush())? else {
+            return Ok(None);
+        };
+
+        // push the cells to target row
+        for cell in &cells {
+            row.push(cell.as_ref());
+        }
 
         // tell Postgres we've done one row, and need to scan the next row
         Ok(Some(0))
     }
 
     fn re_scan(_ctx: &Context) -> FdwResult {
-        Err("re_scan on foreign table is not supported".to_owned())
+        Self::this_mut()
+            .scan
+            .rescan()
+            .inspect_err(|_| metrics::flush())
     }
 
     fn end_scan(_ctx: &Context) -> FdwResult {
-        let this = Self::this_mut();
-        this.src_rows.clear();
+        let mut this = Self::this_mut();
+
+        // summarize the tolerated conversion failures
+        if this.scan.cell_errors > 0 {
+            utils::report_warning(&format!(
+                "{} cells failed to convert and were set to NULL",
+                this.scan.cell_errors
+            ));
+        }
+        for msg in this.scan.coercion_warnings() {
+            utils::report_warning(&msg);
+        }
+        timing::report();
+        ratelimit::report();
+        metrics::flush();
+
+        this.scan = ScanState::default();
         Ok(())
     }
 
-    fn begin_modify(_ctx: &Context) -> FdwResult {
-        Err("modify on foreign table is not supported".to_owned())
+    fn begin_modify(ctx: &Context) -> FdwResult {
+        // checked first, so a read-only server stays read-only whatever
+        // modify support is added below
+        let server_opts = ctx.get_options(OptionsType::Server);
+        if get_bool_option(&server_opts, "read_only")? {
+            return Err(
+                "foreign server is configured read-only, spreadsheets cannot be modified \
+                 through it"
+                    .to_owned(),
+            );
+        }
+
+        Self::start_modify(ctx).inspect_err(|_| metrics::flush())
     }
 
-    fn insert(_ctx: &Context, _row: &Row) -> FdwResult {
+    fn insert(_ctx: &Context, row: &Row) -> FdwResult {
+        let mut this = Self::this_mut();
+        this.modify
+            .insert(&row.cols().into_iter().zip(row.cells()).collect::<Vec<_>>())?;
+        if this.modify.is_full() {
+            this.modify.flush()?;
+        }
         Ok(())
     }
 
-    fn update(_ctx: &Context, _rowid: Cell, _row: &Row) -> FdwResult {
+    fn update(_ctx: &Context, rowid: Cell, row: &Row) -> FdwResult {
+        let mut this = Self::this_mut();
+        this.modify.update(
+            &rowid,
+            &row.cols().into_iter().zip(row.cells()).collect::<Vec<_>>(),
+        )?;
+        if this.modify.is_full() {
+            this.modify.flush()?;
+        }
         Ok(())
     }
 
-    fn delete(_ctx: &Context, _rowid: Cell) -> FdwResult {
+    fn delete(_ctx: &Context, rowid: Cell) -> FdwResult {
+        let mut this = Self::this_mut();
+        this.modify.delete(&rowid, time::epoch_secs())?;
+        if this.modify.is_full() {
+            this.modify.flush()?;
+        }
         Ok(())
     }
 
     fn end_modify(_ctx: &Context) -> FdwResult {
-        Ok(())
+        let mut this = Self::this_mut();
+        let res = this.modify.flush();
+        if res.is_ok() {
+            if let Some(summary) = this.modify.summary(metrics::pending) {
+                utils::report_info(&summary);
+            }
+        }
+        metrics::flush();
+        this.modify = ModifyState::default();
+        res
     }
 }
 
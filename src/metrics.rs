@@ -0,0 +1,177 @@
+// cumulative counters of all scans, for monitoring, each scan runs in a new
+// instance so its counts are added to the totals kept by the host in the
+// stats metadata of the FDW at the end of the scan, or as soon as it fails as
+// it doesn't get to its end then, and read by the metrics object, the same
+// document has sections of other state shared by the scans, which are read
+// when a scan starts and written back with the counts, a modify counts its
+// requests and written rows the same way
+//
+// the document is read, added to and written back without a lock, so scans
+// flushing at the same time may lose each other's counts and sections, the
+// totals are approximate and meant for monitoring rather than accounting
+use std::sync::{Mutex, MutexGuard};
+
+use serde_json::{Map, Value as JsonValue};
+
+use crate::bindings::supabase::wrappers::stats;
+
+// name the totals are kept under in the host stats
+const FDW_NAME: &str = "SheetsFdw";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    Scans,
+    // HTTP requests, every retry included
+    Requests,
+    // bytes of the response bodies
+    BytesDownloaded,
+    Retries,
+    // failed requests by error class, see retry
+    QuotaErrors,
+    ServerErrors,
+    NetworkErrors,
+    // rows written by modifies, an upsert of a key in the sheet is an update
+    // and a soft delete a delete
+    RowsInserted,
+    RowsUpdated,
+    RowsDeleted,
+    // write requests of the rows, audit records excluded
+    WriteBatches,
+}
+
+// number of counters
+pub const COUNTERS: usize = 11;
+
+impl Counter {
+    pub const ALL: [Self; COUNTERS] = [
+        Self::Scans,
+        Self::Requests,
+        Self::BytesDownloaded,
+        Self::Retries,
+        Self::QuotaErrors,
+        Self::ServerErrors,
+        Self::NetworkErrors,
+        Self::RowsInserted,
+        Self::RowsUpdated,
+        Self::RowsDeleted,
+        Self::WriteBatches,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Scans => "scans",
+            Self::Requests => "requests",
+            Self::BytesDownloaded => "bytes_downloaded",
+            Self::Retries => "retries",
+            Self::QuotaErrors => "quota_errors",
+            Self::ServerErrors => "server_errors",
+            Self::NetworkErrors => "network_errors",
+            Self::RowsInserted => "rows_inserted",
+            Self::RowsUpdated => "rows_updated",
+            Self::RowsDeleted => "rows_deleted",
+            Self::WriteBatches => "write_batches",
+        }
+    }
+}
+
+// counts of the scan not added to the totals yet
+static COUNTS: Mutex<[u64; COUNTERS]> = Mutex::new([0; COUNTERS]);
+
+// sections set by the scan not written yet
+static SECTIONS: Mutex<Vec<(String, JsonValue)>> = Mutex::new(Vec::new());
+
+fn counts() -> MutexGuard<'static, [u64; COUNTERS]> {
+    COUNTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn sections() -> MutexGuard<'static, Vec<(String, JsonValue)>> {
+    SECTIONS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn add(counter: Counter, n: u64) {
+    counts()[counter as usize] += n;
+}
+
+// count of the scan or modify not added to the totals yet
+pub fn pending(counter: Counter) -> u64 {
+    counts()[counter as usize]
+}
+
+// add the counts of the scan to the totals and write the sections it set, in
+// a single read and write of the stats metadata
+pub fn flush() {
+    let counts = std::mem::take(&mut *counts());
+    let sections = std::mem::take(&mut *sections());
+    if counts.iter().all(|&n| n == 0) && sections.is_empty() {
+        return;
+    }
+    let mut doc = add_totals(stats::get_metadata(FDW_NAME).as_deref(), &counts);
+    for (name, value) in sections {
+        doc = with_section(Some(&doc), &name, value);
+    }
+    stats::set_metadata(FDW_NAME, &Some(doc));
+}
+
+// add counts to the totals of a stats metadata document, which is replaced if
+// it is not a JSON object of counters
+pub fn add_totals(doc: Option<&str>, counts: &[u64; COUNTERS]) -> String {
+    let mut totals: Map<String, JsonValue> = doc
+        .and_then(|doc| serde_json::from_str(doc).ok())
+        .unwrap_or_default();
+    for counter in Counter::ALL {
+        let total = totals
+            .get(counter.name())
+            .and_then(JsonValue::as_u64)
+            .unwrap_or(0);
+        totals.insert(
+            counter.name().to_owned(),
+            (total + counts[counter as usize]).into(),
+        );
+    }
+    JsonValue::Object(totals).to_string()
+}
+
+// a section of the stats metadata document besides the counters, keeping
+// state shared by the scans, e.g. the token bucket of the rate limit, read
+// once when the scan starts
+pub fn get_section(name: &str) -> Option<JsonValue> {
+    let doc = stats::get_metadata(FDW_NAME)?;
+    serde_json::from_str::<Map<String, JsonValue>>(&doc)
+        .ok()?
+        .remove(name)
+}
+
+// set a section, written with the counts at the next flush
+pub fn set_section(name: &str, value: JsonValue) {
+    let mut sections = sections();
+    match sections.iter_mut().find(|(k, _)| k == name) {
+        Some((_, v)) => *v = value,
+        None => sections.push((name.to_owned(), value)),
+    }
+}
+
+// set a section of a stats metadata document, which is replaced if it is not
+// a JSON object
+pub fn with_section(doc: Option<&str>, name: &str, value: JsonValue) -> String {
+    let mut sections: Map<String, JsonValue> = doc
+        .and_then(|doc| serde_json::from_str(doc).ok())
+        .unwrap_or_default();
+    sections.insert(name.to_owned(), value);
+    JsonValue::Object(sections).to_string()
+}
+
+// the totals of all scans so far, this one included
+pub fn totals() -> Vec<(&'static str, u64)> {
+    flush();
+    let doc = stats::get_metadata(FDW_NAME);
+    let totals: Map<String, JsonValue> = doc
+        .and_then(|doc| serde_json::from_str(&doc).ok())
+        .unwrap_or_default();
+    Counter::ALL
+        .iter()
+        .map(|counter| {
+            let total = totals.get(counter.name()).and_then(JsonValue::as_u64);
+            (counter.name(), total.unwrap_or(0))
+        })
+        .collect()
+}
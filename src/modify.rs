@@ -0,0 +1,1556 @@
+// INSERT, UPDATE and DELETE of the rows of a sheet through Sheets v4 API, as
+// the service account of auth, the modify routines buffer the rows and every
+// write_batch_size rows, and at the end of the modify, the buffers are turned
+// into requests: inserted rows are appended to the range by values:append and
+// updated rows are written by values:batchUpdate at their sheet row, given by
+// the _row_number column as the rowid_column of the table, deleted rows are
+// removed by the deleteDimension requests of a spreadsheets:batchUpdate once
+// all of them are known, as removing rows moves the rows below them
+//
+// with check_unmodified the rowid_column is _row_version instead, and the
+// updated rows are read again before they are written, failing the write if
+// their displayed cells no longer match the version the scan read
+//
+// with the key_column table option an inserted row whose key is already in
+// the sheet updates that row instead of being appended, and with a sheet
+// column as the rowid_column updated rows are found by their key, the keys
+// are read once when the modify starts and the rows appended by it are added
+// to them
+//
+// with route_by_column each inserted row is appended to the tab named by its
+// value in that column instead, and with create_sheet_if_missing the tabs
+// missing are added before the rows are appended to them
+//
+// with insert_position 'top' inserted rows go below the header row instead,
+// rows are added there by insertDimension and the cells written by
+// values:batchUpdate, the sheet rows of the keys below are moved down
+//
+// with soft_delete_column a deleted row is kept instead, marked by the time
+// of the delete written to that column, and scans leave it out
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use crate::api::{api_error_message, SheetGrid, SheetProperties, Spreadsheet};
+use crate::auth;
+use crate::bindings::supabase::wrappers::{
+    http, time,
+    types::{Cell, FdwError, FdwResult, TypeOid},
+    utils,
+};
+use crate::column::{hmac_sha256_hex, row_version, version_positions, ColumnSpec, NullWrite};
+use crate::datetime::{serial_from_epoch_micros, DateTimeParts};
+use crate::gviz::{a1_start, column_letters, encode_query, header_rows};
+use crate::metrics::{self, Counter};
+use crate::options::{parse_bool_option, OptionLookup};
+use crate::retry;
+
+// rows buffered before their requests are made if write_batch_size is not
+// set
+const DEFAULT_BATCH_SIZE: usize = 500;
+
+// how Sheets takes the written values, set by the value_input_option table
+// option
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ValueInput {
+    // as is, text is never parsed
+    #[default]
+    Raw,
+    // as if typed into the sheet, so text can become a formula, a number or
+    // a date
+    UserEntered,
+}
+
+impl ValueInput {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Raw => "RAW",
+            Self::UserEntered => "USER_ENTERED",
+        }
+    }
+}
+
+// state of the modify of a foreign table, rebuilt by every begin_modify
+#[derive(Debug, Default)]
+pub struct ModifyState {
+    // Sheets v4 API URL of the spreadsheet
+    url: String,
+    // tab written and the top-left cell of its range
+    grid: SheetGrid,
+    // the tab of sheet_name is missing, so it is created before the rows are
+    // written and has no keys
+    missing_sheet: bool,
+    // rows of the range above the data
+    header_rows: usize,
+    tgt_cols: Vec<ColumnSpec>,
+    input: ValueInput,
+    // a column writes formulas, so the requests are USER_ENTERED whatever
+    // the input of the other columns
+    formulas: bool,
+    // the write requests are reported instead of made
+    dry_run: bool,
+    // max number of rows buffered
+    batch_size: usize,
+    // keys of the sheet rows, if inserts upsert on key_column or the
+    // rowid_column is a sheet column
+    keys: Option<KeyIndex>,
+    // inserts upsert on the keys
+    upsert: bool,
+    // the rowids are keys rather than sheet rows
+    rowid_key: bool,
+    // cells of the rows to append, by position in the range
+    inserts: Vec<Vec<JsonValue>>,
+    // audit records of each row to append, whose rowids are set once it is
+    // appended
+    insert_audits: Vec<Vec<usize>>,
+    // tab of each row to append, if it is routed by route_by_column
+    insert_tabs: Vec<Option<String>>,
+    // tabs of the inserted rows, if route_by_column is set
+    route: Option<Route>,
+    // inserted rows go below the header row rather than after the last row
+    insert_top: bool,
+    // cells of the rows to update by 1-based sheet row, None where a column
+    // is not written
+    updates: Vec<(usize, Vec<Option<JsonValue>>)>,
+    // rows of the updates that are updated and deleted rows, the others are
+    // the header row of a created tab
+    updated_rows: u64,
+    soft_deleted_rows: u64,
+    // records of the modified rows, if audit_sheet is set
+    audit: Option<Audit>,
+    // positions in the range of the cells of a row version, if the rows are
+    // checked for concurrent edits
+    check_unmodified: Option<Vec<usize>>,
+    // versions of the rows to update, checked before they are written
+    versions: Vec<String>,
+    // target column a delete marks, if soft_delete_column is set
+    soft_delete: Option<usize>,
+    // 1-based sheet rows to remove, at the end of the modify
+    deletes: Vec<usize>,
+}
+
+// audit trail of the rows modified by a statement, appended to the tab of
+// audit_sheet after each batch of writes
+#[derive(Debug, Default)]
+pub struct Audit {
+    title: String,
+    // HMAC key signing each record, read from the Vault secret of
+    // audit_key_id
+    key: Option<String>,
+    records: Vec<AuditRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub operation: &'static str,
+    // 1-based sheet row of the row, unknown for a failed append
+    pub rowid: Option<usize>,
+    // written values by column name
+    pub values: JsonValue,
+}
+
+// tab of routed inserts, its rows and their appended records
+type TabRows = (Option<String>, Vec<Vec<JsonValue>>, Vec<Appended>);
+
+// tabs inserted rows are appended to by the value of route_by_column
+#[derive(Debug, Default)]
+pub struct Route {
+    // the target column of the tab titles
+    col: usize,
+    // missing tabs are added
+    create: bool,
+    // titles of the tabs of the spreadsheet, with the ones to add
+    tabs: HashSet<String>,
+    // tabs to add before the rows are appended to them
+    added: Vec<String>,
+}
+
+// sheet rows of the keys of key_column, to find the row an insert updates
+#[derive(Debug, Default)]
+pub struct KeyIndex {
+    // the target column of the key
+    col: usize,
+    rows: HashMap<String, KeyRow>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRow {
+    // 1-based sheet row of a key in the sheet
+    Sheet(usize),
+    // position of a buffered insert
+    Insert(usize),
+}
+
+// a write request made from the buffered rows
+#[derive(Debug)]
+pub struct Write {
+    pub req: http::Request,
+    // rows appended by the request, whose sheet rows are known once the
+    // append responds
+    pub appended: Option<Vec<Appended>>,
+    // rows written by the request, counted once it is made
+    pub rows: Vec<(Counter, u64)>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Appended {
+    // key of the row, if inserts upsert
+    pub key: Option<String>,
+    // audit records of the row
+    pub audits: Vec<usize>,
+}
+
+// values of a range, as returned by values.get
+#[derive(Debug, Default, Deserialize)]
+pub struct ValueRange {
+    #[serde(default)]
+    pub values: Vec<Vec<JsonValue>>,
+}
+
+// response of values:batchGet, one range per requested range
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchGetResponse {
+    #[serde(default)]
+    value_ranges: Vec<ValueRange>,
+}
+
+// response of values:append
+#[derive(Debug, Deserialize)]
+struct AppendResponse {
+    updates: AppendUpdates,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AppendUpdates {
+    #[serde(default)]
+    updated_range: String,
+}
+
+// response of spreadsheets:batchUpdate, one reply per request
+#[derive(Debug, Deserialize)]
+struct BatchUpdateResponse {
+    #[serde(default)]
+    replies: Vec<BatchUpdateReply>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchUpdateReply {
+    add_sheet: Option<AddSheetReply>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddSheetReply {
+    properties: SheetProperties,
+}
+
+// Drive file of the spreadsheet, with the capabilities of the credential
+#[derive(Debug, Deserialize)]
+struct DriveFile {
+    #[serde(default)]
+    capabilities: FileCapabilities,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileCapabilities {
+    #[serde(default)]
+    can_edit: bool,
+}
+
+impl ModifyState {
+    pub fn new(
+        url: String,
+        meta: &Spreadsheet,
+        tgt_cols: Vec<ColumnSpec>,
+        opts: &impl OptionLookup,
+    ) -> Result<Self, FdwError> {
+        let (grid, missing_sheet) = write_grid(meta, opts)?;
+        // rows are addressed by their sheet row, whose version is checked
+        // with check_unmodified, or found by the key of a sheet column
+        let check = parse_bool_option("check_unmodified", opts.lookup("check_unmodified"))?;
+        let rowid_key = match opts.lookup("rowid_column") {
+            rowid if check && rowid.as_deref() != Some("_row_version") => {
+                return Err("check_unmodified needs rowid_column '_row_version'".to_owned());
+            }
+            None => None,
+            Some(rowid) if rowid == "_row_number" || rowid == "_row_version" => None,
+            Some(rowid) => Some(rowid),
+        };
+        let check_unmodified = check.then(|| version_positions(&tgt_cols));
+        let batch_size = match opts.lookup("write_batch_size") {
+            Some(size) => match size.parse::<usize>() {
+                Ok(size) if size > 0 => size,
+                _ => return Err(format!("invalid write_batch_size '{}'", size)),
+            },
+            None => DEFAULT_BATCH_SIZE,
+        };
+        let input = match opts.lookup("value_input_option").as_deref() {
+            None | Some("raw") => ValueInput::Raw,
+            Some("user_entered") => ValueInput::UserEntered,
+            Some(v) => {
+                return Err(format!(
+                    "invalid value_input_option '{}', expect 'raw' or 'user_entered'",
+                    v
+                ))
+            }
+        };
+        let key_column = opts.lookup("key_column");
+        let key = match (&key_column, &rowid_key) {
+            (Some(key), Some(rowid)) if key != rowid => {
+                return Err(format!(
+                    "key_column {} and rowid_column {} must be the same column",
+                    key, rowid
+                ));
+            }
+            (Some(key), _) => Some(("key_column", key)),
+            (None, Some(rowid)) => Some(("rowid_column", rowid)),
+            (None, None) => None,
+        };
+        let keys = match key {
+            Some((option, name)) => Some(KeyIndex {
+                col: tgt_cols
+                    .iter()
+                    .position(|c| &c.name == name && is_written(c))
+                    .ok_or(format!("{} {} is not a sheet column", option, name))?,
+                ..Default::default()
+            }),
+            None => None,
+        };
+        let soft_delete = match opts.lookup("soft_delete_column") {
+            Some(name) => {
+                let col = tgt_cols
+                    .iter()
+                    .position(|c| c.name == name && is_written(c))
+                    .ok_or(format!("soft_delete_column {} is not a sheet column", name))?;
+                if !matches!(
+                    tgt_cols[col].type_oid,
+                    TypeOid::Bool
+                        | TypeOid::String
+                        | TypeOid::Date
+                        | TypeOid::Timestamp
+                        | TypeOid::Timestamptz
+                ) {
+                    return Err(format!(
+                        "soft_delete_column {} must have boolean, text, date or timestamp type",
+                        name
+                    ));
+                }
+                Some(col)
+            }
+            None => None,
+        };
+        let route = match opts.lookup("route_by_column") {
+            Some(_) if opts.lookup("named_range").is_some() || key_column.is_some() => {
+                return Err(
+                    "route_by_column cannot be used with named_range or key_column".to_owned(),
+                );
+            }
+            Some(name) => Some(Route {
+                col: tgt_cols
+                    .iter()
+                    .position(|c| c.name == name && is_written(c))
+                    .ok_or(format!("route_by_column {} is not a sheet column", name))?,
+                create: parse_bool_option(
+                    "create_sheet_if_missing",
+                    opts.lookup("create_sheet_if_missing"),
+                )?,
+                tabs: meta
+                    .sheets
+                    .iter()
+                    .map(|sheet| sheet.properties.title.clone())
+                    .collect(),
+                ..Default::default()
+            }),
+            None => None,
+        };
+        let header_rows = header_rows(opts)?.unwrap_or(1);
+        let insert_top = match opts.lookup("insert_position").as_deref() {
+            None | Some("bottom") => false,
+            Some("top") if route.is_some() => {
+                return Err("insert_position 'top' cannot be used with route_by_column".to_owned());
+            }
+            Some("top") if header_rows == 0 => {
+                return Err("insert_position 'top' needs a header row".to_owned());
+            }
+            Some("top") => true,
+            Some(v) => {
+                return Err(format!(
+                    "invalid insert_position '{}', expect 'top' or 'bottom'",
+                    v
+                ))
+            }
+        };
+        let formulas = tgt_cols.iter().any(|c| c.formula && is_written(c));
+        let audit = match opts.lookup("audit_sheet") {
+            Some(title) => {
+                meta.sheet_id_of(&title)?;
+                let key = match opts.lookup("audit_key_id") {
+                    Some(key_id) => Some(utils::get_vault_secret(&key_id).ok_or(format!(
+                        "cannot get audit key from Vault secret '{}'",
+                        key_id
+                    ))?),
+                    None => None,
+                };
+                Some(Audit {
+                    title,
+                    key,
+                    ..Default::default()
+                })
+            }
+            None if opts.lookup("audit_key_id").is_some() => {
+                return Err("audit_key_id needs audit_sheet".to_owned());
+            }
+            None => None,
+        };
+        Ok(Self {
+            url,
+            grid,
+            missing_sheet,
+            header_rows,
+            tgt_cols,
+            input,
+            formulas,
+            dry_run: parse_bool_option("dry_run", opts.lookup("dry_run"))?,
+            batch_size,
+            keys,
+            upsert: key_column.is_some(),
+            route,
+            insert_top,
+            rowid_key: rowid_key.is_some(),
+            audit,
+            check_unmodified,
+            soft_delete,
+            ..Default::default()
+        })
+    }
+
+    // 1-based sheet row of the first data row
+    fn first_data_row(&self) -> usize {
+        self.grid.row + self.header_rows + 1
+    }
+
+    // A1 range of the tab from a 1-based sheet row to another, open-ended if
+    // not given, over the columns at the positions in the range
+    fn a1_range(&self, first: usize, last: Option<usize>, cols: (usize, usize)) -> String {
+        self.tab_range(&self.grid.title, first, last, cols)
+    }
+
+    // A1 range of another tab, at the same rows and columns
+    fn tab_range(
+        &self,
+        title: &str,
+        first: usize,
+        last: Option<usize>,
+        cols: (usize, usize),
+    ) -> String {
+        format!(
+            "'{}'!{}{}:{}{}",
+            title.replace('\'', "''"),
+            column_letters((self.grid.col + cols.0) as i64),
+            first,
+            column_letters((self.grid.col + cols.1) as i64),
+            last.map(|row| row.to_string()).unwrap_or_default(),
+        )
+    }
+
+    // create the missing tab of sheet_name, with a header row of the column
+    // names unless header_rows is 0
+    pub fn create_sheet(&mut self) -> FdwResult {
+        let Some(req) = self.add_sheet_request() else {
+            return Ok(());
+        };
+        let sheet_id = match self.dry_run {
+            true => {
+                utils::report_info(&dry_run_message(&req));
+                0
+            }
+            false => added_sheet_id(&send(req)?.body)?,
+        };
+        self.sheet_added(sheet_id);
+        Ok(())
+    }
+
+    // request adding the missing tab of sheet_name, None if it is not missing
+    pub fn add_sheet_request(&self) -> Option<http::Request> {
+        self.missing_sheet
+            .then(|| self.add_sheets_request(std::slice::from_ref(&self.grid.title)))
+    }
+
+    // request adding tabs by title
+    fn add_sheets_request(&self, titles: &[String]) -> http::Request {
+        let requests: Vec<JsonValue> = titles
+            .iter()
+            .map(|title| json!({"addSheet": {"properties": {"title": title}}}))
+            .collect();
+        json_request(
+            http::Method::Post,
+            format!("{}:batchUpdate", self.url),
+            &json!({ "requests": requests }),
+        )
+    }
+
+    // the missing tab is added with the gid, its header row is written with
+    // the rows, before them
+    pub fn sheet_added(&mut self, sheet_id: i64) {
+        self.grid.sheet_id = sheet_id;
+        if self.header_rows > 0 {
+            let names = self.header_names();
+            self.updates.push((self.grid.row + self.header_rows, names));
+        }
+    }
+
+    // cells of the header row of an added tab, the names of the written
+    // columns by position
+    fn header_names(&self) -> Vec<Option<JsonValue>> {
+        let mut names = Vec::new();
+        for tgt_col in self.tgt_cols.iter().filter(|c| is_written(c)) {
+            if names.len() <= tgt_col.src_idx {
+                names.resize(tgt_col.src_idx + 1, None);
+            }
+            names[tgt_col.src_idx] = Some(JsonValue::String(tgt_col.name.clone()));
+        }
+        names
+    }
+
+    // tab an inserted row is appended to by its value of route_by_column,
+    // None if inserts are not routed, a missing tab is to be added
+    fn route_tab(&mut self, values: &[Option<JsonValue>]) -> Result<Option<String>, FdwError> {
+        let Some(route) = self.route.as_mut() else {
+            return Ok(None);
+        };
+        let tgt_col = &self.tgt_cols[route.col];
+        let tab = values
+            .get(tgt_col.src_idx)
+            .and_then(Option::as_ref)
+            .map(key_text)
+            .filter(|tab| !tab.is_empty())
+            .ok_or(format!("route_by_column {} cannot be NULL", tgt_col.name))?;
+        if !route.tabs.contains(&tab) {
+            if !route.create {
+                return Err(format!(
+                    "tab '{}' of route_by_column {} is not found",
+                    tab, tgt_col.name
+                ));
+            }
+            route.tabs.insert(tab.clone());
+            route.added.push(tab.clone());
+        }
+        Ok(Some(tab))
+    }
+
+    // index the sheet keys of key_column, if inserts upsert on it, a created
+    // tab has none
+    pub fn fetch_keys(&mut self) -> FdwResult {
+        if self.missing_sheet {
+            return Ok(());
+        }
+        match self.keys_request() {
+            Some(req) => self.load_keys(&send(req)?.body),
+            None => Ok(()),
+        }
+    }
+
+    // make the requests of the buffered rows, or report them in a dry run
+    pub fn flush(&mut self) -> FdwResult {
+        // the versions are read in a dry run too, as it makes no write
+        if let Some(req) = self.versions_request() {
+            self.check_versions(&send(req)?.body)?;
+        }
+        for write in self.take_writes() {
+            if self.dry_run {
+                utils::report_info(&dry_run_message(&write.req));
+                continue;
+            }
+            let resp = send(write.req)?;
+            metrics::add(Counter::WriteBatches, 1);
+            for (counter, rows) in write.rows {
+                metrics::add(counter, rows);
+            }
+            if let Some(appended) = write.appended {
+                let range = updated_range(&resp.body)?;
+                utils::report_info(&format!("{} rows appended at {}", appended.len(), range));
+                self.appended(appended, &range)?;
+            }
+        }
+        // the rows are audited with every batch, as a failed statement
+        // doesn't get to end_modify
+        self.write_audit()
+    }
+
+    // request of the sheet keys of key_column, None if there is no key column
+    pub fn keys_request(&self) -> Option<http::Request> {
+        let keys = self.keys.as_ref()?;
+        let pos = self.tgt_cols[keys.col].src_idx;
+        let range = self.a1_range(self.first_data_row(), None, (pos, pos));
+        Some(get_request(format!(
+            "{}/values/{}?majorDimension=COLUMNS&valueRenderOption=UNFORMATTED_VALUE",
+            self.url,
+            encode_query(&range)
+        )))
+    }
+
+    // index the sheet keys of key_column from the response of keys_request,
+    // the first row of a key repeated in the sheet is updated
+    pub fn load_keys(&mut self, body: &str) -> FdwResult {
+        let first_row = self.first_data_row();
+        let Some(keys) = self.keys.as_mut() else {
+            return Ok(());
+        };
+        let range: ValueRange =
+            serde_json::from_str(body).map_err(|e| format!("invalid key_column values: {}", e))?;
+        let column = range.values.into_iter().next().unwrap_or_default();
+        for (idx, value) in column.iter().enumerate() {
+            let key = key_text(value);
+            if !key.is_empty() {
+                keys.rows
+                    .entry(key)
+                    .or_insert(KeyRow::Sheet(first_row + idx));
+            }
+        }
+        Ok(())
+    }
+
+    // the buffers are full, so their requests are to be made, deletes wait
+    // for the end of the modify whatever their number
+    pub fn is_full(&self) -> bool {
+        self.inserts.len() + self.updates.len() >= self.batch_size
+    }
+
+    // cells of a row by position in the range, None for the positions of no
+    // written column
+    fn row_values(
+        &self,
+        row: &[(String, Option<Cell>)],
+    ) -> Result<Vec<Option<JsonValue>>, FdwError> {
+        let mut values = Vec::new();
+        for (name, cell) in row {
+            let Some(tgt_col) = self
+                .tgt_cols
+                .iter()
+                .find(|c| &c.name == name && is_written(c))
+            else {
+                continue;
+            };
+            if values.len() <= tgt_col.src_idx {
+                values.resize(tgt_col.src_idx + 1, None);
+            }
+            let mut value = encode_cell(tgt_col, cell.as_ref(), self.input)?;
+            // text taken as is gets the leading apostrophe that keeps it
+            // literal in a USER_ENTERED request
+            if self.formulas && self.input == ValueInput::Raw {
+                if let JsonValue::String(text) = &value {
+                    let is_formula = tgt_col.formula && text.starts_with('=');
+                    if !text.is_empty() && !is_formula {
+                        value = JsonValue::String(format!("'{}", text));
+                    }
+                }
+            }
+            values[tgt_col.src_idx] = Some(value);
+        }
+        Ok(values)
+    }
+
+    // buffer an inserted row, as an update of the sheet row or buffered
+    // insert of its key if it has one
+    pub fn insert(&mut self, row: &[(String, Option<Cell>)]) -> FdwResult {
+        let values = self.row_values(row)?;
+        let Some(keys) = self.keys.as_ref().filter(|_| self.upsert) else {
+            let tab = self.route_tab(&values)?;
+            let audits = self.record("INSERT", None, row)?.into_iter().collect();
+            self.inserts.push(fill_nulls(values));
+            self.insert_audits.push(audits);
+            self.insert_tabs.push(tab);
+            return Ok(());
+        };
+        let tgt_col = &self.tgt_cols[keys.col];
+        let key = values
+            .get(tgt_col.src_idx)
+            .and_then(Option::as_ref)
+            .map(key_text)
+            .filter(|key| !key.is_empty())
+            .ok_or(format!("key_column {} cannot be NULL", tgt_col.name))?;
+        match keys.rows.get(&key).copied() {
+            Some(KeyRow::Sheet(sheet_row)) => {
+                self.record("INSERT", Some(sheet_row), row)?;
+                self.updates.push((sheet_row, values));
+                self.updated_rows += 1;
+            }
+            Some(KeyRow::Insert(idx)) => {
+                let audit = self.record("INSERT", None, row)?;
+                self.insert_audits[idx].extend(audit);
+                let insert = &mut self.inserts[idx];
+                for (pos, value) in values.into_iter().enumerate() {
+                    if let Some(value) = value {
+                        insert[pos] = value;
+                    }
+                }
+            }
+            None => {
+                let idx = self.inserts.len();
+                let audits = self.record("INSERT", None, row)?.into_iter().collect();
+                self.inserts.push(fill_nulls(values));
+                self.insert_audits.push(audits);
+                self.insert_tabs.push(None);
+                if let Some(keys) = self.keys.as_mut() {
+                    keys.rows.insert(key, KeyRow::Insert(idx));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // buffer an update of the sheet row given by the rowid
+    pub fn update(&mut self, rowid: &Cell, row: &[(String, Option<Cell>)]) -> FdwResult {
+        let values = self.row_values(row)?;
+        let sheet_row = match self.rowid_key {
+            true => self.key_row(rowid, &values)?,
+            false => self.rowid_row(rowid)?,
+        };
+        self.record("UPDATE", Some(sheet_row), row)?;
+        self.updates.push((sheet_row, values));
+        self.updated_rows += 1;
+        Ok(())
+    }
+
+    // buffer a delete of the sheet row given by the rowid, or an update
+    // marking the row in soft_delete_column with the time of the delete
+    pub fn delete(&mut self, rowid: &Cell, now_secs: i64) -> FdwResult {
+        let sheet_row = match self.rowid_key {
+            true => self.key_row(rowid, &[])?,
+            false => self.rowid_row(rowid)?,
+        };
+        let Some(col) = self.soft_delete else {
+            self.record("DELETE", Some(sheet_row), &[])?;
+            self.deletes.push(sheet_row);
+            return Ok(());
+        };
+        let tgt_col = &self.tgt_cols[col];
+        let micros = now_secs * 1_000_000;
+        let mark = match tgt_col.type_oid {
+            TypeOid::Bool => Cell::Bool(true),
+            TypeOid::Date => Cell::Date(now_secs - now_secs.rem_euclid(86_400)),
+            TypeOid::Timestamp => Cell::Timestamp(micros),
+            TypeOid::Timestamptz => Cell::Timestamptz(micros),
+            _ => Cell::String(DateTimeParts::from_epoch_micros(micros).datetime_text()),
+        };
+        let row = [(tgt_col.name.clone(), Some(mark))];
+        let values = self.row_values(&row)?;
+        self.record("DELETE", Some(sheet_row), &row)?;
+        self.updates.push((sheet_row, values));
+        self.soft_deleted_rows += 1;
+        Ok(())
+    }
+
+    // 1-based sheet row of the key of a rowid, the key index follows a key
+    // changed by the updated values
+    fn key_row(&mut self, rowid: &Cell, values: &[Option<JsonValue>]) -> Result<usize, FdwError> {
+        let input = self.input;
+        let Some(keys) = self.keys.as_mut() else {
+            return Err("rowid_column is not a sheet column".to_owned());
+        };
+        let tgt_col = &self.tgt_cols[keys.col];
+        let key = key_text(&encode_cell(tgt_col, Some(rowid), input)?);
+        let sheet_row = match keys.rows.get(&key) {
+            Some(KeyRow::Sheet(sheet_row)) => *sheet_row,
+            _ => return Err(format!("no sheet row has {} '{}'", tgt_col.name, key)),
+        };
+        if let Some(new_key) = values.get(tgt_col.src_idx).and_then(Option::as_ref) {
+            let new_key = key_text(new_key);
+            if new_key.is_empty() {
+                return Err(format!("rowid_column {} cannot be NULL", tgt_col.name));
+            }
+            if new_key != key {
+                keys.rows.remove(&key);
+                keys.rows.insert(new_key, KeyRow::Sheet(sheet_row));
+            }
+        }
+        Ok(sheet_row)
+    }
+
+    // 1-based sheet row of a rowid, which is a _row_number, or a
+    // _row_version whose version is kept to be checked
+    fn rowid_row(&mut self, rowid: &Cell) -> Result<usize, FdwError> {
+        let sheet_row = match rowid {
+            Cell::I64(row) if *row > 0 => *row as usize,
+            Cell::String(version) => match version.split_once(':').map(|(row, _)| row.parse()) {
+                Some(Ok(row)) if row > 0 => {
+                    if self.check_unmodified.is_some() {
+                        self.versions.push(version.clone());
+                    }
+                    row
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid rowid '{}', expect a _row_version",
+                        version
+                    ))
+                }
+            },
+            _ => {
+                return Err(
+                    "invalid rowid, expect the _row_number or _row_version of the row".to_owned(),
+                )
+            }
+        };
+        if self.check_unmodified.is_some() && !matches!(rowid, Cell::String(_)) {
+            return Err("check_unmodified needs the _row_version of the row as rowid".to_owned());
+        }
+        if sheet_row < self.first_data_row() {
+            return Err(format!(
+                "row {} is not a data row of the range, which starts at row {}",
+                sheet_row,
+                self.first_data_row()
+            ));
+        }
+        Ok(sheet_row)
+    }
+
+    // request reading the rows whose versions are to be checked, as
+    // displayed, None if there are none
+    pub fn versions_request(&self) -> Option<http::Request> {
+        let positions = self.check_unmodified.as_ref()?;
+        if self.versions.is_empty() {
+            return None;
+        }
+        let last = positions.last().copied().unwrap_or(0);
+        let ranges: Vec<String> = self
+            .versions
+            .iter()
+            .filter_map(|version| version.split_once(':')?.0.parse().ok())
+            .map(|row: usize| {
+                format!(
+                    "ranges={}",
+                    encode_query(&self.a1_range(row, Some(row), (0, last)))
+                )
+            })
+            .collect();
+        Some(get_request(format!(
+            "{}/values:batchGet?{}&majorDimension=ROWS&valueRenderOption=FORMATTED_VALUE",
+            self.url,
+            ranges.join("&")
+        )))
+    }
+
+    // check the versions of the rows from the response of versions_request,
+    // emptying them, failing on the first row edited since it was read
+    pub fn check_versions(&mut self, body: &str) -> FdwResult {
+        let Some(positions) = self.check_unmodified.as_ref() else {
+            return Ok(());
+        };
+        let resp: BatchGetResponse =
+            serde_json::from_str(body).map_err(|e| format!("invalid batchGet response: {}", e))?;
+        let versions = std::mem::take(&mut self.versions);
+        for (version, range) in versions.iter().zip(resp.value_ranges) {
+            let Some((row, _)) = version.split_once(':') else {
+                continue;
+            };
+            let cells = range.values.into_iter().next().unwrap_or_default();
+            let texts: Vec<String> = positions
+                .iter()
+                .map(|&pos| cells.get(pos).map(key_text).unwrap_or_default())
+                .collect();
+            let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+            let sheet_row = row.parse().unwrap_or_default();
+            if row_version(sheet_row, &texts) != *version {
+                return Err(format!(
+                    "row {} was modified since it was read, read it again to update it",
+                    sheet_row
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // make the requests of the buffered rows, emptying the buffers, updates
+    // go first as appends don't move existing rows, and deletes last as they
+    // do, the tabs inserts are routed to are added before them all
+    pub fn take_writes(&mut self) -> Vec<Write> {
+        let mut writes = self.route_writes();
+        let updates = std::mem::take(&mut self.updates);
+        if !updates.is_empty() {
+            let data: Vec<JsonValue> = updates
+                .into_iter()
+                .filter_map(|(row, values)| {
+                    let first = values.iter().position(Option::is_some)?;
+                    let last = values.iter().rposition(Option::is_some)?;
+                    let cells: Vec<JsonValue> = values[first..=last]
+                        .iter()
+                        .map(|v| v.clone().unwrap_or(JsonValue::Null))
+                        .collect();
+                    Some(json!({
+                        "range": self.a1_range(row, Some(row), (first, last)),
+                        "values": [cells],
+                    }))
+                })
+                .collect();
+            writes.push(Write {
+                req: json_request(
+                    http::Method::Post,
+                    format!("{}/values:batchUpdate", self.url),
+                    &json!({ "valueInputOption": self.request_input().name(), "data": data }),
+                ),
+                appended: None,
+                rows: vec![
+                    (Counter::RowsUpdated, std::mem::take(&mut self.updated_rows)),
+                    (
+                        Counter::RowsDeleted,
+                        std::mem::take(&mut self.soft_deleted_rows),
+                    ),
+                ],
+            });
+        }
+
+        let inserts = std::mem::take(&mut self.inserts);
+        if !inserts.is_empty() {
+            // keys and audit records of buffered inserts are resolved once
+            // they are appended
+            let mut appended: Vec<Appended> = std::mem::take(&mut self.insert_audits)
+                .into_iter()
+                .map(|audits| Appended { key: None, audits })
+                .collect();
+            if let Some(keys) = self.keys.as_mut() {
+                keys.rows.retain(|key, row| match row {
+                    KeyRow::Insert(idx) => {
+                        appended[*idx].key = Some(key.clone());
+                        false
+                    }
+                    KeyRow::Sheet(_) => true,
+                });
+            }
+            let inserted = if self.insert_top {
+                self.top_writes(inserts, appended)
+            } else {
+                self.append_writes(inserts, appended)
+            };
+            writes.extend(inserted);
+        }
+
+        if let Some((req, rows)) = self.delete_request() {
+            writes.push(Write {
+                req,
+                appended: None,
+                rows: vec![(Counter::RowsDeleted, rows)],
+            });
+        }
+        writes
+    }
+
+    // requests appending the inserted rows, the rows of each tab together in
+    // the order of their first row
+    fn append_writes(
+        &mut self,
+        inserts: Vec<Vec<JsonValue>>,
+        appended: Vec<Appended>,
+    ) -> Vec<Write> {
+        let mut writes = Vec::new();
+        let tabs = std::mem::take(&mut self.insert_tabs);
+        let mut groups: Vec<TabRows> = Vec::new();
+        for ((values, appended), tab) in inserts.into_iter().zip(appended).zip(tabs) {
+            let idx = match groups.iter().position(|(t, _, _)| *t == tab) {
+                Some(idx) => idx,
+                None => {
+                    groups.push((tab, Vec::new(), Vec::new()));
+                    groups.len() - 1
+                }
+            };
+            groups[idx].1.push(values);
+            groups[idx].2.push(appended);
+        }
+        let width = self.width();
+        for (tab, values, appended) in groups {
+            let title = tab.as_deref().unwrap_or(&self.grid.title);
+            let range =
+                self.tab_range(title, self.grid.row + 1, None, (0, width.saturating_sub(1)));
+            writes.push(Write {
+                req: json_request(
+                    http::Method::Post,
+                    format!(
+                        "{}/values/{}:append?valueInputOption={}&insertDataOption=INSERT_ROWS",
+                        self.url,
+                        encode_query(&range),
+                        self.request_input().name()
+                    ),
+                    &json!({ "majorDimension": "ROWS", "values": values }),
+                ),
+                rows: vec![(Counter::RowsInserted, values.len() as u64)],
+                appended: Some(appended),
+            });
+        }
+        writes
+    }
+
+    // requests adding rows below the header row and writing the inserted
+    // rows to them, the rows are placed at once as their sheet rows are known
+    fn top_writes(&mut self, inserts: Vec<Vec<JsonValue>>, appended: Vec<Appended>) -> Vec<Write> {
+        self.insert_tabs.clear();
+        let (first, count) = (self.first_data_row(), inserts.len());
+        // the rows below move down, with the keys and deletes of them
+        if let Some(keys) = self.keys.as_mut() {
+            for row in keys.rows.values_mut() {
+                match row {
+                    KeyRow::Sheet(row) if *row >= first => *row += count,
+                    _ => {}
+                }
+            }
+        }
+        for row in self.deletes.iter_mut().filter(|row| **row >= first) {
+            *row += count;
+        }
+        self.placed(appended, first);
+        let width = self.width();
+        let range = self.a1_range(first, Some(first + count - 1), (0, width.saturating_sub(1)));
+        vec![
+            Write {
+                req: json_request(
+                    http::Method::Post,
+                    format!("{}:batchUpdate", self.url),
+                    &json!({"requests": [{"insertDimension": {
+                        "range": {
+                            "sheetId": self.grid.sheet_id,
+                            "dimension": "ROWS",
+                            "startIndex": first - 1,
+                            "endIndex": first - 1 + count,
+                        },
+                        "inheritFromBefore": false,
+                    }}]}),
+                ),
+                appended: None,
+                rows: Vec::new(),
+            },
+            Write {
+                req: json_request(
+                    http::Method::Post,
+                    format!("{}/values:batchUpdate", self.url),
+                    &json!({
+                        "valueInputOption": self.request_input().name(),
+                        "data": [{"range": range, "majorDimension": "ROWS", "values": inserts}],
+                    }),
+                ),
+                appended: None,
+                rows: vec![(Counter::RowsInserted, count as u64)],
+            },
+        ]
+    }
+
+    // requests adding the tabs inserts are routed to and writing their header
+    // rows, emptying them
+    fn route_writes(&mut self) -> Vec<Write> {
+        let Some(added) = self
+            .route
+            .as_mut()
+            .map(|route| std::mem::take(&mut route.added))
+        else {
+            return Vec::new();
+        };
+        if added.is_empty() {
+            return Vec::new();
+        }
+        let mut writes = vec![Write {
+            req: self.add_sheets_request(&added),
+            appended: None,
+            rows: Vec::new(),
+        }];
+        if self.header_rows > 0 {
+            let names = self.header_names();
+            let (first, last) = (
+                names.iter().position(Option::is_some).unwrap_or(0),
+                names.len().saturating_sub(1),
+            );
+            let cells: Vec<JsonValue> = names[first..]
+                .iter()
+                .map(|v| v.clone().unwrap_or(JsonValue::Null))
+                .collect();
+            let row = self.grid.row + self.header_rows;
+            let data: Vec<JsonValue> = added
+                .iter()
+                .map(|title| {
+                    json!({
+                        "range": self.tab_range(title, row, Some(row), (first, last)),
+                        "values": [cells],
+                    })
+                })
+                .collect();
+            writes.push(Write {
+                req: json_request(
+                    http::Method::Post,
+                    format!("{}/values:batchUpdate", self.url),
+                    &json!({ "valueInputOption": "RAW", "data": data }),
+                ),
+                appended: None,
+                rows: Vec::new(),
+            });
+        }
+        writes
+    }
+
+    // request removing the rows to delete and their number, emptying them,
+    // None if there are none, adjacent rows are removed by one
+    // deleteDimension, bottom up so that each range is still at its rows when
+    // it is removed
+    fn delete_request(&mut self) -> Option<(http::Request, u64)> {
+        let mut rows = std::mem::take(&mut self.deletes);
+        if rows.is_empty() {
+            return None;
+        }
+        rows.sort_unstable_by(|a, b| b.cmp(a));
+        rows.dedup();
+        let count = rows.len() as u64;
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for row in rows {
+            match ranges.last_mut() {
+                Some((first, _)) if *first == row + 1 => *first = row,
+                _ => ranges.push((row, row)),
+            }
+        }
+        let requests: Vec<JsonValue> = ranges
+            .into_iter()
+            .map(|(first, last)| {
+                json!({"deleteDimension": {"range": {
+                    "sheetId": self.grid.sheet_id,
+                    "dimension": "ROWS",
+                    "startIndex": first - 1,
+                    "endIndex": last,
+                }}})
+            })
+            .collect();
+        let req = json_request(
+            http::Method::Post,
+            format!("{}:batchUpdate", self.url),
+            &json!({ "requests": requests }),
+        );
+        Some((req, count))
+    }
+
+    // summary of the rows written by the modify and the requests made, by
+    // the counts of the modify, None in a dry run which writes nothing
+    pub fn summary(&self, count: impl Fn(Counter) -> u64) -> Option<String> {
+        (!self.dry_run).then(|| {
+            format!(
+                "{} rows inserted, {} updated and {} deleted in {} write batches, {} API calls",
+                count(Counter::RowsInserted),
+                count(Counter::RowsUpdated),
+                count(Counter::RowsDeleted),
+                count(Counter::WriteBatches),
+                count(Counter::Requests)
+            )
+        })
+    }
+
+    // add the keys of appended rows at the sheet rows of the range they are
+    // appended at, which are the rowids of their audit records
+    pub fn appended(&mut self, appended: Vec<Appended>, range: &str) -> FdwResult {
+        self.placed(appended, first_row(range)?);
+        Ok(())
+    }
+
+    // resolve the keys and audit records of rows written from a 1-based
+    // sheet row on
+    fn placed(&mut self, appended: Vec<Appended>, first_row: usize) {
+        for (idx, row) in appended.into_iter().enumerate() {
+            if let (Some(keys), Some(key)) = (self.keys.as_mut(), row.key) {
+                keys.rows.insert(key, KeyRow::Sheet(first_row + idx));
+            }
+            if let Some(audit) = self.audit.as_mut() {
+                for record in row.audits {
+                    audit.records[record].rowid = Some(first_row + idx);
+                }
+            }
+        }
+    }
+
+    // add the audit record of a modified row, returning its index if the
+    // modify is audited, the values are the written ones by column name
+    fn record(
+        &mut self,
+        operation: &'static str,
+        rowid: Option<usize>,
+        row: &[(String, Option<Cell>)],
+    ) -> Result<Option<usize>, FdwError> {
+        if self.audit.is_none() {
+            return Ok(None);
+        }
+        let mut values = serde_json::Map::new();
+        for (name, cell) in row {
+            if let Some(tgt_col) = self
+                .tgt_cols
+                .iter()
+                .find(|c| &c.name == name && is_written(c))
+            {
+                values.insert(
+                    name.clone(),
+                    encode_cell(tgt_col, cell.as_ref(), self.input)?,
+                );
+            }
+        }
+        let Some(audit) = self.audit.as_mut() else {
+            return Ok(None);
+        };
+        audit.records.push(AuditRecord {
+            operation,
+            rowid,
+            values: JsonValue::Object(values),
+        });
+        Ok(Some(audit.records.len() - 1))
+    }
+
+    // request appending the audit records to the audit tab, emptying them,
+    // None if there are none
+    pub fn audit_request(&mut self, now_secs: i64, identity: &str) -> Option<http::Request> {
+        let audit = self.audit.as_mut()?;
+        let records = std::mem::take(&mut audit.records);
+        if records.is_empty() {
+            return None;
+        }
+        let rows: Vec<Vec<JsonValue>> = records
+            .iter()
+            .map(|record| audit_row(record, now_secs, identity, audit.key.as_deref()))
+            .collect();
+        let range = format!("'{}'!A1:F", audit.title.replace('\'', "''"));
+        Some(json_request(
+            http::Method::Post,
+            format!(
+                "{}/values/{}:append?valueInputOption=RAW&insertDataOption=INSERT_ROWS",
+                self.url,
+                encode_query(&range)
+            ),
+            &json!({ "majorDimension": "ROWS", "values": rows }),
+        ))
+    }
+
+    // append the audit records of the written rows to the audit tab
+    pub fn write_audit(&mut self) -> FdwResult {
+        let Some(req) = self.audit_request(time::epoch_secs(), &auth::identity()) else {
+            return Ok(());
+        };
+        match self.dry_run {
+            true => utils::report_info(&dry_run_message(&req)),
+            false => {
+                send(req)?;
+            }
+        }
+        Ok(())
+    }
+
+    // input of the write requests
+    fn request_input(&self) -> ValueInput {
+        match self.formulas {
+            true => ValueInput::UserEntered,
+            false => self.input,
+        }
+    }
+
+    // number of positions in the range the written columns span
+    fn width(&self) -> usize {
+        self.tgt_cols
+            .iter()
+            .filter(|c| is_written(c))
+            .map(|c| c.src_idx + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+// the column is written to the sheet, virtual columns are not in it, expr
+// columns are computed by gviz and masked columns would overwrite the data
+// with its mask
+fn is_written(tgt_col: &ColumnSpec) -> bool {
+    tgt_col.virtual_col.is_none() && tgt_col.expr.is_none() && tgt_col.mask.is_none()
+}
+
+// cells of a row to append, positions of no written column are left alone
+fn fill_nulls(values: Vec<Option<JsonValue>>) -> Vec<JsonValue> {
+    values
+        .into_iter()
+        .map(|v| v.unwrap_or(JsonValue::Null))
+        .collect()
+}
+
+// the value of a written cell, NULL as given by null_on_write, dates and
+// times as serial numbers of the sheet if they are written as is, or as text
+// the sheet parses into a formatted date otherwise
+pub fn encode_cell(
+    tgt_col: &ColumnSpec,
+    cell: Option<&Cell>,
+    input: ValueInput,
+) -> Result<JsonValue, FdwError> {
+    let number = |v: f64| {
+        serde_json::Number::from_f64(v)
+            .map(JsonValue::Number)
+            .ok_or(format!(
+                "column {}: cannot write {} to a sheet",
+                tgt_col.name, v
+            ))
+    };
+    Ok(match cell {
+        None => match tgt_col.null_write {
+            NullWrite::Clear => JsonValue::String(String::new()),
+            NullWrite::Keep => JsonValue::Null,
+            NullWrite::Sentinel => JsonValue::String(tgt_col.null_values[0].clone()),
+        },
+        Some(Cell::Bool(v)) => JsonValue::Bool(*v),
+        Some(Cell::I8(v)) => (*v).into(),
+        Some(Cell::I16(v)) => (*v).into(),
+        Some(Cell::I32(v)) => (*v).into(),
+        Some(Cell::I64(v)) => (*v).into(),
+        Some(Cell::F32(v)) => number(*v as f64)?,
+        Some(Cell::F64(v)) | Some(Cell::Numeric(v)) => number(*v)?,
+        Some(Cell::String(v)) | Some(Cell::Json(v)) => JsonValue::String(v.clone()),
+        Some(Cell::Date(secs)) if input == ValueInput::UserEntered => {
+            JsonValue::String(DateTimeParts::from_epoch_micros(secs * 1_000_000).date_text())
+        }
+        Some(Cell::Timestamp(micros)) | Some(Cell::Timestamptz(micros))
+            if input == ValueInput::UserEntered =>
+        {
+            JsonValue::String(DateTimeParts::from_epoch_micros(*micros).datetime_text())
+        }
+        Some(Cell::Date(secs)) => number(serial_from_epoch_micros(secs * 1_000_000))?,
+        Some(Cell::Timestamp(micros)) | Some(Cell::Timestamptz(micros)) => {
+            number(serial_from_epoch_micros(*micros))?
+        }
+    })
+}
+
+// text a key is matched by, numbers without a fraction have none, so the
+// number 7 read from the sheet matches the integer 7 written
+pub fn key_text(value: &JsonValue) -> String {
+    match value {
+        JsonValue::Null => String::new(),
+        JsonValue::String(s) => s.clone(),
+        JsonValue::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(v), _) => v.to_string(),
+            (None, Some(v)) if v.fract() == 0.0 && v.abs() < 1e15 => (v as i64).to_string(),
+            _ => n.to_string(),
+        },
+        v => v.to_string(),
+    }
+}
+
+// range the rows are appended at, from a values:append response, e.g.
+// "'Data'!A12:C14"
+pub fn updated_range(body: &str) -> Result<String, FdwError> {
+    let resp: AppendResponse =
+        serde_json::from_str(body).map_err(|e| format!("invalid append response: {}", e))?;
+    Ok(resp.updates.updated_range)
+}
+
+// 1-based sheet row of the top-left cell of an A1 range
+pub fn first_row(range: &str) -> Result<usize, FdwError> {
+    let cells = range.rsplit_once('!').map_or(range, |(_, cells)| cells);
+    match a1_start(cells) {
+        (row, _) if cells.chars().any(|c| c.is_ascii_digit()) => Ok(row + 1),
+        _ => Err(format!("invalid range '{}' of the append", range)),
+    }
+}
+
+// cells of an audit record: UTC time, identity the spreadsheet is modified
+// as, operation, rowid, values as JSON and, with a key, the HMAC-SHA-256 of
+// the other cells as a JSON array, so an edited record no longer matches
+pub fn audit_row(
+    record: &AuditRecord,
+    now_secs: i64,
+    identity: &str,
+    key: Option<&str>,
+) -> Vec<JsonValue> {
+    let mut row = vec![
+        JsonValue::String(DateTimeParts::from_epoch_micros(now_secs * 1_000_000).datetime_text()),
+        JsonValue::String(identity.to_owned()),
+        JsonValue::String(record.operation.to_owned()),
+        record
+            .rowid
+            .map_or(JsonValue::String(String::new()), |row| row.into()),
+        JsonValue::String(record.values.to_string()),
+    ];
+    if let Some(key) = key {
+        let signature = hmac_sha256_hex(
+            key.as_bytes(),
+            JsonValue::from(row.clone()).to_string().as_bytes(),
+        );
+        row.push(JsonValue::String(signature));
+    }
+    row
+}
+
+// a write request as reported by a dry run
+pub fn dry_run_message(req: &http::Request) -> String {
+    format!(
+        "dry run: {} {} {}",
+        method_name(&req.method),
+        req.url,
+        req.body
+    )
+}
+
+pub fn method_name(method: &http::Method) -> &'static str {
+    match method {
+        http::Method::Get => "GET",
+        http::Method::Post => "POST",
+        http::Method::Put => "PUT",
+        http::Method::Patch => "PATCH",
+        http::Method::Delete => "DELETE",
+    }
+}
+
+fn get_request(url: String) -> http::Request {
+    http::Request {
+        method: http::Method::Get,
+        url,
+        headers: vec![("user-agent".to_owned(), "Sheets FDW".to_owned())],
+        body: String::default(),
+    }
+}
+
+fn json_request(method: http::Method, url: String, body: &JsonValue) -> http::Request {
+    http::Request {
+        method,
+        url,
+        headers: vec![
+            ("user-agent".to_owned(), "Sheets FDW".to_owned()),
+            ("content-type".to_owned(), "application/json".to_owned()),
+        ],
+        body: body.to_string(),
+    }
+}
+
+// make a request of the modify as the service account, failing on an error
+// response
+pub fn send(req: http::Request) -> Result<http::Response, FdwError> {
+    let resp = auth::send(&req, |req| match req.method {
+        http::Method::Get => retry::get(req),
+        _ => retry::write(req, is_idempotent(req)),
+    })?;
+    if !(200..300).contains(&resp.status_code) {
+        return Err(api_error_message(resp.status_code, &resp.body));
+    }
+    Ok(resp)
+}
+
+// making the request twice changes nothing, values:batchUpdate writes the
+// same values to the same cells again, while an append adds its rows again
+// and a deleteDimension removes the rows below the ones it removed
+pub fn is_idempotent(req: &http::Request) -> bool {
+    match req.method {
+        http::Method::Post | http::Method::Patch => req
+            .url
+            .split('?')
+            .next()
+            .is_some_and(|path| path.ends_with("/values:batchUpdate")),
+        _ => true,
+    }
+}
+
+// check before any row is written that the token can write spreadsheets
+// and the credential can edit the spreadsheet, its tabs are checked with the
+// metadata
+pub fn preflight(drive_url: &str, spreadsheet_id: &str) -> FdwResult {
+    auth::check_token()?;
+    let identity = auth::identity();
+    let resp = send(capabilities_request(drive_url, spreadsheet_id)).map_err(|e| {
+        format!(
+            "cannot check that {} can edit spreadsheet {}: {}",
+            identity, spreadsheet_id, e
+        )
+    })?;
+    check_can_edit(&resp.body, spreadsheet_id, &identity)
+}
+
+// request of the Drive capabilities of the credential on the spreadsheet
+pub fn capabilities_request(drive_url: &str, spreadsheet_id: &str) -> http::Request {
+    get_request(format!(
+        "{}/{}?fields={}&supportsAllDrives=true",
+        drive_url,
+        spreadsheet_id,
+        encode_query("capabilities(canEdit)")
+    ))
+}
+
+// check the credential can edit the spreadsheet from the response of
+// capabilities_request
+pub fn check_can_edit(body: &str, spreadsheet_id: &str, identity: &str) -> FdwResult {
+    let file: DriveFile =
+        serde_json::from_str(body).map_err(|e| format!("invalid Drive file: {}", e))?;
+    match file.capabilities.can_edit {
+        true => Ok(()),
+        false => Err(format!(
+            "{} cannot edit spreadsheet {}, share it with edit access",
+            identity, spreadsheet_id
+        )),
+    }
+}
+
+// request of the tabs and named ranges of the spreadsheet, to find the tab
+// written
+pub fn metadata_request(url: &str) -> http::Request {
+    get_request(format!(
+        "{}?fields={}",
+        url,
+        encode_query("namedRanges,sheets.properties(sheetId,title,gridProperties)")
+    ))
+}
+
+// tab and range written, given by the same table options as the rows read,
+// and whether the tab of sheet_name is missing and to be created
+pub fn write_grid(
+    meta: &Spreadsheet,
+    opts: &impl OptionLookup,
+) -> Result<(SheetGrid, bool), FdwError> {
+    if opts.lookup("filter_view").is_some() {
+        return Err("tables of a filter_view cannot be modified".to_owned());
+    }
+    let sheet_name = opts.lookup("sheet_name");
+    let create = parse_bool_option(
+        "create_sheet_if_missing",
+        opts.lookup("create_sheet_if_missing"),
+    )?;
+    if create && sheet_name.is_none() && opts.lookup("route_by_column").is_none() {
+        return Err("create_sheet_if_missing needs sheet_name or route_by_column".to_owned());
+    }
+    let (sheet_id, range) = match opts.lookup("named_range") {
+        Some(_)
+            if opts.lookup("sheet_id").is_some()
+                || sheet_name.is_some()
+                || opts.lookup("range").is_some() =>
+        {
+            return Err("named_range cannot be used with sheet_id, sheet_name or range".to_owned());
+        }
+        Some(name) => meta.resolve_named_range(&name)?,
+        None => match sheet_name {
+            Some(_) if opts.lookup("sheet_id").is_some() => {
+                return Err("sheet_name cannot be used with sheet_id".to_owned());
+            }
+            Some(name) => match meta.sheet_id_of(&name) {
+                Ok(gid) => (Some(gid), opts.lookup("range")),
+                Err(_) if create => {
+                    let (row, col) = opts.lookup("range").as_deref().map_or((0, 0), a1_start);
+                    let grid = SheetGrid {
+                        title: name,
+                        row,
+                        col,
+                        ..Default::default()
+                    };
+                    return Ok((grid, true));
+                }
+                Err(e) => return Err(e),
+            },
+            None => (opts.lookup("sheet_id"), opts.lookup("range")),
+        },
+    };
+    Ok((
+        meta.sheet_grid(sheet_id.as_deref(), range.as_deref())?,
+        false,
+    ))
+}
+
+// gid of the tab added by an addSheet request, from its batchUpdate response
+pub fn added_sheet_id(body: &str) -> Result<i64, FdwError> {
+    let resp: BatchUpdateResponse =
+        serde_json::from_str(body).map_err(|e| format!("invalid addSheet response: {}", e))?;
+    resp.replies
+        .into_iter()
+        .find_map(|reply| reply.add_sheet)
+        .map(|added| added.properties.sheet_id)
+        .ok_or("addSheet response has no tab".to_owned())
+}
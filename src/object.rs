@@ -0,0 +1,271 @@
+// spreadsheet objects scanned from the metadata instead of the sheet data
+use serde_json::Value as JsonValue;
+
+use crate::api::{fetch_drive_files, fetch_spreadsheet, Spreadsheet};
+use crate::bindings::supabase::wrappers::types::FdwError;
+use crate::column::{is_datetime_type, ColumnSpec};
+use crate::datetime::parse_datetime;
+use crate::gviz::{GvizCell, GvizRow};
+use crate::metrics;
+
+// spreadsheet object scanned instead of the sheet data, set by the object
+// table option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Object {
+    // one row per tab of the spreadsheet
+    Sheets,
+    // one row per developer metadata entry, wherever it is located
+    DeveloperMetadata,
+    // one row per protected range of all tabs
+    ProtectedRanges,
+    // one row per spreadsheet in a Drive folder
+    DriveFolder,
+    // one row per spreadsheet with the result of reading its metadata with
+    // the configured API keys, for monitoring probes
+    Health,
+    // one row per counter of all scans so far, see metrics
+    Metrics,
+}
+
+impl Object {
+    pub fn parse(value: Option<&str>) -> Result<Option<Self>, FdwError> {
+        match value {
+            None => Ok(None),
+            Some("sheets") => Ok(Some(Self::Sheets)),
+            Some("developer_metadata") => Ok(Some(Self::DeveloperMetadata)),
+            Some("protected_ranges") => Ok(Some(Self::ProtectedRanges)),
+            Some("drive_folder") => Ok(Some(Self::DriveFolder)),
+            Some("health") => Ok(Some(Self::Health)),
+            Some("metrics") => Ok(Some(Self::Metrics)),
+            Some(v) => Err(format!("invalid object '{}'", v)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sheets => "sheets",
+            Self::DeveloperMetadata => "developer_metadata",
+            Self::ProtectedRanges => "protected_ranges",
+            Self::DriveFolder => "drive_folder",
+            Self::Health => "health",
+            Self::Metrics => "metrics",
+        }
+    }
+
+    // attributes of the object which can be target columns
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            Self::Sheets => &[
+                "title",
+                "gid",
+                "index",
+                "row_count",
+                "column_count",
+                "hidden",
+            ],
+            Self::DeveloperMetadata => &[
+                "metadata_id",
+                "key",
+                "value",
+                "visibility",
+                "location_type",
+                "gid",
+                "dimension",
+                "start_index",
+                "end_index",
+            ],
+            Self::ProtectedRanges => &[
+                "protected_range_id",
+                "gid",
+                "sheet",
+                "range",
+                "named_range_id",
+                "description",
+                "warning_only",
+                "editors",
+                "domain_users_can_edit",
+            ],
+            Self::DriveFolder => &["id", "name", "modified_time", "owners"],
+            Self::Health => &["status", "title", "sheet_count", "error"],
+            Self::Metrics => &["name", "value"],
+        }
+    }
+
+    // Sheets v4 or Drive API field mask to request the object
+    pub fn fields(&self) -> &'static str {
+        match self {
+            Self::Sheets => "sheets.properties",
+            // metadata on rows and columns is only found in grid data
+            Self::DeveloperMetadata => {
+                "developerMetadata,sheets(developerMetadata,\
+                 data(rowMetadata.developerMetadata,columnMetadata.developerMetadata))"
+            }
+            Self::ProtectedRanges => {
+                "sheets(properties(sheetId,title,gridProperties),protectedRanges)"
+            }
+            Self::DriveFolder => "nextPageToken,files(id,name,modifiedTime,owners)",
+            Self::Health => "properties.title,sheets.properties.sheetId",
+            // kept by the host rather than fetched
+            Self::Metrics => "",
+        }
+    }
+
+    // fetch the object and convert it to source rows
+    pub fn load_rows(&self, url: &str, tgt_cols: &[ColumnSpec]) -> Result<Vec<GvizRow>, FdwError> {
+        let make_row = |attr: &dyn Fn(&ColumnSpec) -> JsonValue| object_row(tgt_cols, attr);
+
+        let rows = match self {
+            Self::Sheets => fetch_spreadsheet(url)?
+                .sheets
+                .into_iter()
+                .map(|sheet| {
+                    let props = sheet.properties;
+                    make_row(&|col| match col.name.as_str() {
+                        "title" => props.title.clone().into(),
+                        "gid" => props.sheet_id.into(),
+                        "index" => props.index.into(),
+                        "row_count" => props.grid_properties.row_count.into(),
+                        "column_count" => props.grid_properties.column_count.into(),
+                        "hidden" => props.hidden.into(),
+                        _ => JsonValue::Null,
+                    })
+                })
+                .collect(),
+            Self::DeveloperMetadata => {
+                let spreadsheet = fetch_spreadsheet(url)?;
+                let mut entries = spreadsheet.developer_metadata;
+                for sheet in spreadsheet.sheets {
+                    entries.extend(sheet.developer_metadata);
+                    for data in sheet.data {
+                        for dim in data.row_metadata.into_iter().chain(data.column_metadata) {
+                            entries.extend(dim.developer_metadata);
+                        }
+                    }
+                }
+
+                // metadata on a range of rows or columns is repeated on each
+                // of them
+                entries.sort_by_key(|e| e.metadata_id);
+                entries.dedup_by_key(|e| e.metadata_id);
+
+                entries
+                    .into_iter()
+                    .map(|entry| {
+                        let loc = entry.location;
+                        let range = loc.dimension_range.as_ref();
+                        make_row(&|col| match col.name.as_str() {
+                            "metadata_id" => entry.metadata_id.into(),
+                            "key" => entry.metadata_key.clone().into(),
+                            "value" => entry.metadata_value.clone().into(),
+                            "visibility" => entry.visibility.clone().into(),
+                            "location_type" => loc.location_type.clone().into(),
+                            "gid" => loc.sheet_id.or(range.map(|r| r.sheet_id)).into(),
+                            "dimension" => range.map(|r| r.dimension.clone()).into(),
+                            "start_index" => range.and_then(|r| r.start_index).into(),
+                            "end_index" => range.and_then(|r| r.end_index).into(),
+                            _ => JsonValue::Null,
+                        })
+                    })
+                    .collect()
+            }
+            Self::ProtectedRanges => {
+                let mut rows = Vec::new();
+                for sheet in fetch_spreadsheet(url)?.sheets {
+                    let props = &sheet.properties;
+                    for prot in sheet.protected_ranges {
+                        let editors = prot.editors.as_ref();
+                        rows.push(make_row(&|col| match col.name.as_str() {
+                            "protected_range_id" => prot.protected_range_id.into(),
+                            "gid" => prot.range.sheet_id.into(),
+                            "sheet" => props.title.clone().into(),
+                            "range" => prot.range.to_a1(&props.grid_properties).into(),
+                            "named_range_id" => prot.named_range_id.clone().into(),
+                            "description" => prot.description.clone().into(),
+                            "warning_only" => prot.warning_only.into(),
+                            // users and groups, as a comma-separated list
+                            "editors" => editors
+                                .map(|e| e.users.iter().chain(&e.groups).cloned())
+                                .map(|e| e.collect::<Vec<_>>().join(","))
+                                .into(),
+                            "domain_users_can_edit" => {
+                                editors.map(|e| e.domain_users_can_edit).into()
+                            }
+                            _ => JsonValue::Null,
+                        }));
+                    }
+                }
+                rows
+            }
+            Self::DriveFolder => fetch_drive_files(url)?
+                .into_iter()
+                .map(|file| {
+                    make_row(&|col| match col.name.as_str() {
+                        "id" => file.id.clone().into(),
+                        "name" => file.name.clone().into(),
+                        // datetime columns take it as a gviz date value
+                        "modified_time" if is_datetime_type(col.type_oid) => file
+                            .modified_time
+                            .as_deref()
+                            .and_then(|t| parse_datetime(t, "%Y-%m-%dT%H:%M:%S%.fZ"))
+                            .map(|t| t.to_gviz_date())
+                            .into(),
+                        "modified_time" => file.modified_time.clone().into(),
+                        // owner email addresses, as a comma-separated list
+                        "owners" => file
+                            .owners
+                            .iter()
+                            .filter_map(|o| {
+                                o.email_address.as_deref().or(o.display_name.as_deref())
+                            })
+                            .collect::<Vec<_>>()
+                            .join(",")
+                            .into(),
+                        _ => JsonValue::Null,
+                    })
+                })
+                .collect(),
+            Self::Health => vec![health_row(tgt_cols, &fetch_spreadsheet(url))],
+            Self::Metrics => metrics::totals()
+                .into_iter()
+                .map(|(name, value)| {
+                    make_row(&|col| match col.name.as_str() {
+                        "name" => name.into(),
+                        "value" => value.into(),
+                        _ => JsonValue::Null,
+                    })
+                })
+                .collect(),
+        };
+        Ok(rows)
+    }
+}
+
+// make the row of a health check from the result of reading the metadata, a
+// failed check is a row rather than an error
+pub fn health_row(tgt_cols: &[ColumnSpec], res: &Result<Spreadsheet, FdwError>) -> GvizRow {
+    object_row(tgt_cols, &|col| match (col.name.as_str(), res) {
+        ("status", Ok(_)) => "ok".into(),
+        ("status", Err(_)) => "error".into(),
+        ("title", Ok(spreadsheet)) => spreadsheet.properties.title.clone().into(),
+        ("sheet_count", Ok(spreadsheet)) => spreadsheet.sheets.len().into(),
+        ("error", Err(e)) => e.clone().into(),
+        _ => JsonValue::Null,
+    })
+}
+
+// make a source row for an object, whose cells are placed at the position of
+// the target column with the attribute name
+pub fn object_row(tgt_cols: &[ColumnSpec], attr: &dyn Fn(&ColumnSpec) -> JsonValue) -> GvizRow {
+    let len = tgt_cols.iter().map(|c| c.src_idx + 1).max().unwrap_or(0);
+    let mut c: Vec<Option<GvizCell>> = (0..len).map(|_| None).collect();
+    for tgt_col in tgt_cols {
+        c[tgt_col.src_idx] = Some(GvizCell {
+            v: attr(tgt_col),
+            f: None,
+        });
+    }
+    GvizRow {
+        c,
+        ..Default::default()
+    }
+}
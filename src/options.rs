@@ -0,0 +1,106 @@
+// foreign server and table option parsing
+use crate::bindings::supabase::wrappers::types::{FdwError, Options};
+
+// option values looked up by key, implemented by the host options and by
+// plain maps in tests
+pub trait OptionLookup {
+    fn lookup(&self, key: &str) -> Option<String>;
+}
+
+impl OptionLookup for Options {
+    fn lookup(&self, key: &str) -> Option<String> {
+        self.get(key)
+    }
+}
+
+// options falling back to fixed values for keys not set, used for defaults
+// that differ by source
+pub struct WithDefaults<'a, O> {
+    pub opts: &'a O,
+    pub defaults: &'a [(&'a str, &'a str)],
+}
+
+impl<O: OptionLookup> OptionLookup for WithDefaults<'_, O> {
+    fn lookup(&self, key: &str) -> Option<String> {
+        self.opts.lookup(key).or_else(|| {
+            self.defaults
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        })
+    }
+}
+
+// parse a boolean option, which must be either 'true' or 'false' if specified
+pub fn parse_bool_option(key: &str, value: Option<String>) -> Result<bool, FdwError> {
+    match value.as_deref() {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(v) => Err(format!("invalid {} '{}', expect 'true' or 'false'", key, v)),
+    }
+}
+
+// get a boolean option, which must be either 'true' or 'false' if specified
+pub fn get_bool_option(opts: &Options, key: &str) -> Result<bool, FdwError> {
+    parse_bool_option(key, opts.get(key))
+}
+
+// get a single character option, e.g. a delimiter
+#[cfg(feature = "csv")]
+pub fn get_char_option(opts: &Options, key: &str) -> Result<Option<char>, FdwError> {
+    match opts.get(key) {
+        None => Ok(None),
+        Some(v) => {
+            let mut chars = v.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(Some(c)),
+                _ => Err(format!(
+                    "invalid {} '{}', expect a single character",
+                    key, v
+                )),
+            }
+        }
+    }
+}
+
+// parse a comma-separated list option, e.g. 'N/A,-,n/a'
+pub fn parse_list_option(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+// get the ids of spreadsheets to scan, either a single spread_sheet_id or a
+// spreadsheet_ids list of spreadsheets having the same layout
+pub fn get_spread_sheet_ids(opts: &Options) -> Result<Vec<String>, FdwError> {
+    match opts.get("spreadsheet_ids") {
+        Some(_) if opts.get("spread_sheet_id").is_some() => {
+            Err("spreadsheet_ids cannot be used with spread_sheet_id".to_owned())
+        }
+        Some(ids) => {
+            let ids = parse_list_option(&ids);
+            if ids.is_empty() {
+                return Err("spreadsheet_ids cannot be empty".to_owned());
+            }
+            Ok(ids)
+        }
+        None => Ok(vec![opts.require("spread_sheet_id")?]),
+    }
+}
+
+// get an option of a target column, the host doesn't pass column options so
+// they are given as "<column>.<key>" table options, which override the table
+// option, which in turn overrides the server option
+pub fn get_column_option(
+    server_opts: &impl OptionLookup,
+    table_opts: &impl OptionLookup,
+    col_name: &str,
+    key: &str,
+) -> Option<String> {
+    table_opts
+        .lookup(&format!("{}.{}", col_name, key))
+        .or_else(|| table_opts.lookup(key))
+        .or_else(|| server_opts.lookup(key))
+}
@@ -0,0 +1,234 @@
+// client-side request rate limit, a token bucket refilled at the rate of the
+// max_requests_per_minute server option so chunked fetches stay under the
+// Sheets API quota, the request budget of a scan set by the
+// max_api_calls_per_scan server option and the quota usage reported with the
+// report_quota server option, the budget and the usage live as long as the
+// component instance which the host creates for each foreign scan, while the
+// bucket is read from the stats metadata when the scan starts and written
+// back with the metrics when it ends, so that scans share it on a best-effort
+// basis, scans running at the same time start from the same tokens and the
+// last one to end wins, so the limit is approximate under concurrent load
+use std::sync::Mutex;
+
+use serde_json::{json, Value as JsonValue};
+
+use crate::api::quota_details;
+use crate::bindings::supabase::wrappers::{
+    http, time,
+    types::{FdwError, FdwResult, Options},
+    utils,
+};
+use crate::metrics;
+use crate::options::get_bool_option;
+use crate::timing::{self, Wait};
+
+// section of the stats metadata the bucket is kept in
+const BUCKET_SECTION: &str = "rate_limit";
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    // max number of requests per minute, also the bucket capacity
+    per_minute: f64,
+    tokens: f64,
+    // epoch seconds of the last refill
+    refilled_at: i64,
+}
+
+impl TokenBucket {
+    // a full bucket
+    pub fn new(per_minute: f64, now: i64) -> Self {
+        Self {
+            per_minute,
+            tokens: per_minute,
+            refilled_at: now,
+        }
+    }
+
+    // take over the tokens left by other scans, capped by the capacity of
+    // this one, a state which is not a bucket is ignored
+    pub fn load(&mut self, state: Option<&JsonValue>) {
+        let Some(state) = state else {
+            return;
+        };
+        if let (Some(tokens), Some(refilled_at)) =
+            (state["tokens"].as_f64(), state["refilled_at"].as_i64())
+        {
+            self.tokens = tokens.clamp(0.0, self.per_minute);
+            self.refilled_at = refilled_at;
+        }
+    }
+
+    pub fn state(&self) -> JsonValue {
+        json!({ "tokens": self.tokens, "refilled_at": self.refilled_at })
+    }
+
+    // take a token, or the number of milliseconds until one is available
+    pub fn take(&mut self, now: i64) -> Result<(), u64> {
+        let elapsed = (now - self.refilled_at).max(0) as f64;
+        self.tokens = (self.tokens + elapsed * self.per_minute / 60.0).min(self.per_minute);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(((1.0 - self.tokens) * 60_000.0 / self.per_minute).ceil() as u64)
+    }
+}
+
+#[derive(Debug)]
+pub struct CallBudget {
+    max: u32,
+    // number of requests made so far in the scan
+    calls: u32,
+}
+
+impl CallBudget {
+    pub fn new(max: u32) -> Self {
+        Self { max, calls: 0 }
+    }
+
+    // count a request, failing the scan once the budget is spent
+    pub fn spend(&mut self) -> FdwResult {
+        if self.calls >= self.max {
+            return Err(format!(
+                "scan exceeded max_api_calls_per_scan {}, narrow the query or raise the option",
+                self.max
+            ));
+        }
+        self.calls += 1;
+        Ok(())
+    }
+}
+
+// quota usage seen by a scan
+#[derive(Debug, Default)]
+struct Usage {
+    requests: u32,
+    // responses refused for quota, by status 429 or a quota error
+    throttled: u32,
+    // quota of the last quota error, e.g. "ReadRequestsPerMinutePerUser 60 of
+    // sheets.googleapis.com/read_requests"
+    quota: Option<String>,
+    // rate limit headers of the last response having them
+    headers: Vec<(String, String)>,
+}
+
+static BUCKET: Mutex<Option<TokenBucket>> = Mutex::new(None);
+static BUDGET: Mutex<Option<CallBudget>> = Mutex::new(None);
+static USAGE: Mutex<Option<Usage>> = Mutex::new(None);
+
+fn bucket() -> std::sync::MutexGuard<'static, Option<TokenBucket>> {
+    BUCKET.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn budget() -> std::sync::MutexGuard<'static, Option<CallBudget>> {
+    BUDGET.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn usage() -> std::sync::MutexGuard<'static, Option<Usage>> {
+    USAGE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// set up the rate limit and the request budget from the server options, no
+// limit if not set
+pub fn configure(server_opts: &Options) -> Result<(), FdwError> {
+    let max_calls = match server_opts.get("max_api_calls_per_scan") {
+        Some(max) => match max.parse::<u32>() {
+            Ok(max) if max > 0 => Some(max),
+            _ => return Err(format!("invalid max_api_calls_per_scan '{}'", max)),
+        },
+        None => None,
+    };
+    *budget() = max_calls.map(CallBudget::new);
+
+    let per_minute = match server_opts.get("max_requests_per_minute") {
+        Some(max) => match max.parse::<u32>() {
+            Ok(max) if max > 0 => Some(max as f64),
+            _ => return Err(format!("invalid max_requests_per_minute '{}'", max)),
+        },
+        None => None,
+    };
+    *bucket() = per_minute.map(|per_minute| {
+        let mut bucket = TokenBucket::new(per_minute, time::epoch_secs());
+        bucket.load(metrics::get_section(BUCKET_SECTION).as_ref());
+        bucket
+    });
+
+    *usage() = get_bool_option(server_opts, "report_quota")?.then(Usage::default);
+    Ok(())
+}
+
+// wait until a request is allowed by the rate limit, every request of a scan
+// including metadata lookups and column chunks is counted against the budget
+pub fn acquire() -> FdwResult {
+    if let Some(budget) = budget().as_mut() {
+        budget.spend()?;
+    }
+
+    let mut bucket = bucket();
+    if let Some(bucket) = bucket.as_mut() {
+        loop {
+            match bucket.take(time::epoch_secs()) {
+                Ok(()) => break,
+                Err(wait) => {
+                    time::sleep(wait);
+                    timing::add_wait(Wait::RateLimit, wait);
+                }
+            }
+        }
+        metrics::set_section(BUCKET_SECTION, bucket.state());
+    }
+    Ok(())
+}
+
+// record the quota usage shown by a response, if reporting it
+pub fn record(resp: &http::Response) {
+    let mut usage = usage();
+    let Some(usage) = usage.as_mut() else {
+        return;
+    };
+    usage.requests += 1;
+    let headers: Vec<(String, String)> = resp
+        .headers
+        .iter()
+        .filter(|(k, _)| k.to_ascii_lowercase().starts_with("x-ratelimit-"))
+        .cloned()
+        .collect();
+    if !headers.is_empty() {
+        usage.headers = headers;
+    }
+    if (200..300).contains(&resp.status_code) {
+        return;
+    }
+    let quota = quota_details(&resp.body);
+    if resp.status_code == 429 || quota.is_some() {
+        usage.throttled += 1;
+    }
+    if quota.is_some() {
+        usage.quota = quota;
+    }
+}
+
+// report the quota usage of the scan to the user, if reporting it
+pub fn report() {
+    let Some(usage) = usage().take() else {
+        return;
+    };
+    let mut parts = vec![format!(
+        "{} requests, {} throttled",
+        usage.requests, usage.throttled
+    )];
+    if let Some(bucket) = bucket().as_ref() {
+        parts.push(format!("max_requests_per_minute {}", bucket.per_minute));
+    }
+    if let Some(budget) = budget().as_ref() {
+        parts.push(format!("max_api_calls_per_scan {}", budget.max));
+    }
+    if let Some(quota) = usage.quota {
+        parts.push(format!("last quota error on {}", quota));
+    }
+    for (k, v) in usage.headers {
+        parts.push(format!("{} {}", k, v));
+    }
+    utils::report_info(&format!("quota usage: {}", parts.join(", ")));
+}
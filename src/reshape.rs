@@ -0,0 +1,256 @@
+// sheets whose rows are not records, reshaped into records once all of their
+// rows are fetched, key/value property sheets, e.g. a settings tab, have a key
+// in the first column and its value in the second, transposed sheets have
+// fields in rows and records in columns and wide sheets have a column for each
+// value of a field, e.g. a column for each month of a planning sheet
+use serde_json::{Map, Value as JsonValue};
+
+use crate::bindings::supabase::wrappers::types::{FdwError, Options, TypeOid};
+use crate::column::ColumnSpec;
+use crate::gviz::{column_index, column_letters, GvizCell, GvizRow};
+use crate::options::{get_bool_option, parse_list_option};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reshape {
+    // a single row with the values of the keys named as the target columns, or
+    // a JSON document of all keys if the table has a single jsonb column
+    KeyValue,
+    // a row for each sheet column but the first one, which has the field
+    // labels, target columns read the sheet rows by position
+    Transpose,
+    // a row for each of the columns of each sheet row, e.g. jan to dec
+    Unpivot(Unpivot),
+}
+
+// columns melted into rows, set by the unpivot_columns, unpivot_key and
+// unpivot_value table options
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unpivot {
+    // sheet columns by header label or column letters, e.g. 'jan,feb' or 'D,E'
+    pub columns: Vec<String>,
+    // target column of the header label of a melted column
+    pub key: String,
+    // target column of the cell of a melted column
+    pub value: String,
+}
+
+impl Reshape {
+    // get the reshape of a scan from the layout, transpose and unpivot table
+    // options
+    pub fn from_options(opts: &Options) -> Result<Option<Self>, FdwError> {
+        let mut reshapes = Vec::new();
+        match opts.get("layout").as_deref() {
+            None | Some("rows") => {}
+            Some("key_value") => reshapes.push(Self::KeyValue),
+            Some(v) => {
+                return Err(format!(
+                    "invalid layout '{}', expect 'rows' or 'key_value'",
+                    v
+                ))
+            }
+        }
+        if get_bool_option(opts, "transpose")? {
+            reshapes.push(Self::Transpose);
+        }
+        if let Some(columns) = opts.get("unpivot_columns") {
+            let columns = parse_list_option(&columns);
+            if columns.is_empty() {
+                return Err("unpivot_columns cannot be empty".to_owned());
+            }
+            reshapes.push(Self::Unpivot(Unpivot {
+                columns,
+                key: opts.require("unpivot_key")?,
+                value: opts.require("unpivot_value")?,
+            }));
+        }
+
+        if reshapes.len() > 1 {
+            let options: Vec<&str> = reshapes.iter().map(Self::option).collect();
+            return Err(format!("{} cannot be used together", options.join(" and ")));
+        }
+        Ok(reshapes.pop())
+    }
+
+    // the option setting the reshape, named in errors
+    pub fn option(&self) -> &'static str {
+        match self {
+            Self::KeyValue => "layout 'key_value'",
+            Self::Transpose => "transpose",
+            Self::Unpivot(_) => "unpivot_columns",
+        }
+    }
+
+    // reshape all source rows of a spreadsheet into records, with the header
+    // labels of its columns
+    pub fn apply(
+        &self,
+        rows: Vec<GvizRow>,
+        tgt_cols: &[ColumnSpec],
+        labels: &[String],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        match self {
+            Self::KeyValue => Ok(vec![fold_key_values(rows, tgt_cols)?]),
+            Self::Transpose => Ok(transpose_rows(rows)),
+            Self::Unpivot(unpivot) => unpivot_rows(rows, tgt_cols, unpivot, labels),
+        }
+    }
+}
+
+// fold the rows of a key/value sheet into a single source row, rows without a
+// key are skipped
+pub fn fold_key_values(rows: Vec<GvizRow>, tgt_cols: &[ColumnSpec]) -> Result<GvizRow, FdwError> {
+    let mut pairs: Vec<(String, GvizCell)> = Vec::with_capacity(rows.len());
+    for row in rows {
+        let mut cells = row.c.into_iter();
+        let Some(key) = cells.next().flatten().and_then(key_text) else {
+            continue;
+        };
+        if pairs.iter().any(|(k, _)| *k == key) {
+            return Err(format!("key '{}' appears more than once", key));
+        }
+        let value = cells.next().flatten().unwrap_or(GvizCell {
+            v: JsonValue::Null,
+            f: None,
+        });
+        pairs.push((key, value));
+    }
+
+    let len = tgt_cols.iter().map(|c| c.src_idx + 1).max().unwrap_or(0);
+    let mut row = GvizRow {
+        c: (0..len).map(|_| None).collect(),
+        ..Default::default()
+    };
+    let mut cols = tgt_cols.iter().filter(|c| c.virtual_col.is_none());
+    match (cols.next(), cols.next()) {
+        // a single jsonb column gets the document of all keys
+        (Some(tgt_col), None) if matches!(tgt_col.type_oid, TypeOid::Json) => {
+            let doc: Map<String, JsonValue> = pairs
+                .into_iter()
+                .map(|(key, value)| (key, json_value(value)))
+                .collect();
+            row.c[tgt_col.src_idx] = Some(GvizCell {
+                v: JsonValue::Object(doc),
+                f: None,
+            });
+        }
+        // other columns get the value of their key, missing keys are NULL
+        _ => {
+            for (key, value) in pairs {
+                if let Some(tgt_col) = tgt_cols
+                    .iter()
+                    .find(|c| c.virtual_col.is_none() && c.name == key)
+                {
+                    row.c[tgt_col.src_idx] = Some(value);
+                }
+            }
+        }
+    }
+    Ok(row)
+}
+
+// text of a key cell, as displayed in the sheet, None if it is blank
+fn key_text(cell: GvizCell) -> Option<String> {
+    let key = match (cell.f, cell.v) {
+        (Some(f), _) => f,
+        (None, JsonValue::String(v)) => v,
+        (None, JsonValue::Null) => return None,
+        (None, v) => v.to_string(),
+    };
+    let key = key.trim();
+    (!key.is_empty()).then(|| key.to_owned())
+}
+
+// value of a key in the JSON document, numbers and booleans are kept as such,
+// whole numbers as integers since gviz has only doubles, and other values are
+// taken as displayed, e.g. dates
+fn json_value(cell: GvizCell) -> JsonValue {
+    match (cell.v, cell.f) {
+        (JsonValue::Number(n), _) => match n.as_f64() {
+            Some(v) if v.fract() == 0.0 && v.abs() < (1u64 << 53) as f64 => (v as i64).into(),
+            _ => JsonValue::Number(n),
+        },
+        (v @ JsonValue::Bool(_), _) => v,
+        (_, Some(f)) => JsonValue::String(f),
+        (v, None) => v,
+    }
+}
+
+// swap the rows and columns of a sheet, the first column has the labels of the
+// fields and is not a record
+pub fn transpose_rows(mut rows: Vec<GvizRow>) -> Vec<GvizRow> {
+    let width = rows.iter().map(|row| row.c.len()).max().unwrap_or(0);
+    (1..width)
+        .map(|idx| GvizRow {
+            c: rows
+                .iter_mut()
+                .map(|row| row.c.get_mut(idx).and_then(Option::take))
+                .collect(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+// melt the unpivot columns of each row into rows of their header label and
+// cell, the other target columns repeat the cells of the row
+pub fn unpivot_rows(
+    rows: Vec<GvizRow>,
+    tgt_cols: &[ColumnSpec],
+    unpivot: &Unpivot,
+    labels: &[String],
+) -> Result<Vec<GvizRow>, FdwError> {
+    let tgt_col = |name: &str, option: &str| {
+        tgt_cols
+            .iter()
+            .find(|c| c.virtual_col.is_none() && c.name == name)
+            .ok_or(format!("{} {} is not a table column", option, name))
+    };
+    let key_idx = tgt_col(&unpivot.key, "unpivot_key")?.src_idx;
+    let value_idx = tgt_col(&unpivot.value, "unpivot_value")?.src_idx;
+
+    // the melted columns are found by header label, then by column letters,
+    // and keyed by their label if they have one
+    let columns: Vec<(usize, String)> = unpivot
+        .columns
+        .iter()
+        .map(|name| {
+            let idx = labels
+                .iter()
+                .position(|label| label == name)
+                .or_else(|| column_index(name))
+                .ok_or(format!(
+                    "unpivot column '{}' is neither a header label nor column letters",
+                    name
+                ))?;
+            let key = match labels.get(idx) {
+                Some(label) if !label.is_empty() => label.clone(),
+                _ => column_letters(idx as i64),
+            };
+            Ok((idx, key))
+        })
+        .collect::<Result<_, FdwError>>()?;
+
+    let len = tgt_cols.iter().map(|c| c.src_idx + 1).max().unwrap_or(0);
+    let mut melted = Vec::with_capacity(rows.len() * columns.len());
+    for mut row in rows {
+        // cells of the other target columns, repeated by every melted row
+        let base: Vec<Option<GvizCell>> = (0..len)
+            .map(|pos| match pos {
+                _ if pos == key_idx || pos == value_idx => None,
+                _ => row.c.get(pos).cloned().flatten(),
+            })
+            .collect();
+        for (idx, key) in &columns {
+            let mut c = base.clone();
+            c[key_idx] = Some(GvizCell {
+                v: JsonValue::String(key.clone()),
+                f: None,
+            });
+            c[value_idx] = row.c.get_mut(*idx).and_then(Option::take);
+            melted.push(GvizRow {
+                c,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(melted)
+}
@@ -0,0 +1,79 @@
+// generic REST endpoint returning JSON, rows are found at a JSON pointer in the
+// response and cells at JSON pointers relative to each row
+use serde_json::Value as JsonValue;
+
+use crate::bindings::supabase::wrappers::{
+    http,
+    types::{FdwError, Options},
+};
+use crate::column::ColumnSpec;
+use crate::gviz::GvizRow;
+use crate::object::object_row;
+use crate::source::Source;
+
+// REST source, with the JSON pointer of the row array in responses
+#[derive(Debug, Default)]
+pub struct RestSource {
+    rows_path: String,
+}
+
+impl RestSource {
+    pub fn new(opts: &Options) -> Self {
+        Self {
+            rows_path: opts.get("rows_path").unwrap_or_default(),
+        }
+    }
+}
+
+impl Source for RestSource {
+    fn build_request(&mut self, url: &str, _fetched: usize) -> http::Request {
+        let headers: Vec<(String, String)> = vec![
+            ("user-agent".to_owned(), "Sheets FDW".to_owned()),
+            ("accept".to_owned(), "application/json".to_owned()),
+        ];
+
+        http::Request {
+            method: http::Method::Get,
+            url: url.to_owned(),
+            headers,
+            body: String::default(),
+        }
+    }
+
+    fn parse_rows(
+        &mut self,
+        resp: &http::Response,
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        http::error_for_status(resp)?;
+        parse_rest_rows(&resp.body, &self.rows_path, tgt_cols)
+    }
+}
+
+// extract source rows from a JSON response body, rows_path points to an array
+// of rows, e.g. "/data/items", or is empty if the body itself is the array
+pub fn parse_rest_rows(
+    body: &str,
+    rows_path: &str,
+    tgt_cols: &[ColumnSpec],
+) -> Result<Vec<GvizRow>, FdwError> {
+    let resp: JsonValue = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    let rows = resp
+        .pointer(rows_path)
+        .and_then(JsonValue::as_array)
+        .ok_or(format!(
+            "rows_path '{}' is not an array in response",
+            rows_path
+        ))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            object_row(tgt_cols, &|col| {
+                row.pointer(&col.json_path)
+                    .cloned()
+                    .unwrap_or(JsonValue::Null)
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,213 @@
+// retries of failed requests by error class, quota refusals (status 429),
+// transient server errors (status 5xx) and connection errors each have their
+// own number of retries and backoff, set by the <class>_retries and
+// <class>_backoff_ms server options, e.g. quota_retries '8' with
+// quota_backoff_ms '10000', the backoff doubles after each retry of a class
+// and nothing is retried if not set, the backoff of all retries of a scan is
+// capped by the retry_budget_ms server option so a flapping endpoint fails
+// the query in a bounded time
+//
+// a write is retried only if it cannot have been applied, a refusal for
+// quota, or if applying it twice changes nothing, as a server or connection
+// error can come after the write is made, and a repeated append would add
+// its rows twice
+use std::sync::{Mutex, MutexGuard};
+
+use crate::bindings::supabase::wrappers::{
+    http, time,
+    types::{FdwError, FdwResult, Options},
+};
+use crate::breaker;
+use crate::failover;
+use crate::metrics::{self, Counter};
+use crate::ratelimit;
+use crate::timing::{self, Wait};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Quota,
+    Server,
+    Network,
+}
+
+impl ErrorClass {
+    const ALL: [Self; 3] = [Self::Quota, Self::Server, Self::Network];
+
+    // prefix of the server options of the class
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Quota => "quota",
+            Self::Server => "server_error",
+            Self::Network => "network",
+        }
+    }
+
+    // counter of the failed requests of the class
+    fn counter(&self) -> Counter {
+        match self {
+            Self::Quota => Counter::QuotaErrors,
+            Self::Server => Counter::ServerErrors,
+            Self::Network => Counter::NetworkErrors,
+        }
+    }
+
+    // class of a failed request, None if it is not failed or not retryable
+    pub fn of(res: &Result<http::Response, FdwError>) -> Option<Self> {
+        match res {
+            Err(_) => Some(Self::Network),
+            Ok(resp) if resp.status_code == 429 => Some(Self::Quota),
+            Ok(resp) if resp.status_code >= 500 => Some(Self::Server),
+            Ok(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub retries: u32,
+    // wait before the first retry, doubled for each following one
+    pub backoff_ms: u64,
+}
+
+impl Policy {
+    // wait before the next retry after the given number of retries, None if
+    // there are no retries left
+    pub fn backoff(&self, retried: u32) -> Option<u64> {
+        (retried < self.retries).then(|| self.backoff_ms.saturating_mul(1 << retried.min(16)))
+    }
+}
+
+const NO_RETRY: Policy = Policy {
+    retries: 0,
+    backoff_ms: 0,
+};
+
+// backoff of a class whose retries are set without a backoff
+const DEFAULT_BACKOFF_MS: u64 = 1000;
+
+// total backoff of the retries of a scan if retry_budget_ms is not set
+const DEFAULT_BUDGET_MS: u64 = 120_000;
+
+static POLICIES: Mutex<[Policy; 3]> = Mutex::new([NO_RETRY; 3]);
+
+// backoff left to the retries of the scan
+static BUDGET_MS: Mutex<u64> = Mutex::new(DEFAULT_BUDGET_MS);
+
+fn policies() -> MutexGuard<'static, [Policy; 3]> {
+    POLICIES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn budget_ms() -> MutexGuard<'static, u64> {
+    BUDGET_MS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// spend a backoff from the budget of the scan, false if it is not left
+pub fn spend_budget(budget_ms: &mut u64, wait: u64) -> bool {
+    if wait > *budget_ms {
+        return false;
+    }
+    *budget_ms -= wait;
+    true
+}
+
+// set up the retry policy of each error class from the server options
+pub fn configure(server_opts: &Options) -> FdwResult {
+    let mut policies = policies();
+    for class in ErrorClass::ALL {
+        let key = format!("{}_retries", class.name());
+        let retries = match server_opts.get(&key) {
+            Some(retries) => match retries.parse::<u32>() {
+                Ok(retries) => retries,
+                _ => return Err(format!("invalid {} '{}'", key, retries)),
+            },
+            None => 0,
+        };
+        let key = format!("{}_backoff_ms", class.name());
+        let backoff_ms = match server_opts.get(&key) {
+            Some(backoff) => match backoff.parse::<u64>() {
+                Ok(backoff) => backoff,
+                _ => return Err(format!("invalid {} '{}'", key, backoff)),
+            },
+            None => DEFAULT_BACKOFF_MS,
+        };
+        policies[class as usize] = Policy {
+            retries,
+            backoff_ms,
+        };
+    }
+    *budget_ms() = match server_opts.get("retry_budget_ms") {
+        Some(budget) => match budget.parse::<u64>() {
+            Ok(budget) => budget,
+            _ => return Err(format!("invalid retry_budget_ms '{}'", budget)),
+        },
+        None => DEFAULT_BUDGET_MS,
+    };
+    Ok(())
+}
+
+// a write failed with the class of error may be retried, quota refusals are
+// never applied while the other errors are retried only if the write is
+// idempotent
+pub fn retries_write(class: ErrorClass, idempotent: bool) -> bool {
+    class == ErrorClass::Quota || idempotent
+}
+
+// send a GET request through the circuit breaker, the rate limit and the
+// base URL failover, retrying it as configured for the class of its error,
+// every attempt counts against the rate limit and the request budget
+pub fn get(req: &http::Request) -> Result<http::Response, FdwError> {
+    breaker::check(&req.url)?;
+    let res = send(|_| true, || failover::get(req));
+    breaker::record(&req.url, ErrorClass::of(&res).is_some());
+    res
+}
+
+// send a write request through the rate limit, retrying it as configured
+// for the class of its error if retries_write allows it
+pub fn write(req: &http::Request, idempotent: bool) -> Result<http::Response, FdwError> {
+    send(
+        |class| retries_write(class, idempotent),
+        || match req.method {
+            http::Method::Put => http::put(req),
+            http::Method::Patch => http::patch(req),
+            http::Method::Delete => http::delete(req),
+            _ => http::post(req),
+        },
+    )
+}
+
+// make the attempts of a request until it succeeds or its error is not to
+// be retried
+fn send(
+    retries: impl Fn(ErrorClass) -> bool,
+    attempt: impl Fn() -> Result<http::Response, FdwError>,
+) -> Result<http::Response, FdwError> {
+    let policies = *policies();
+    let mut retried = [0u32; 3];
+    loop {
+        ratelimit::acquire()?;
+        let res = timing::fetch(&attempt);
+        metrics::add(Counter::Requests, 1);
+        if let Ok(resp) = &res {
+            ratelimit::record(resp);
+            metrics::add(Counter::BytesDownloaded, resp.body.len() as u64);
+        }
+
+        let Some(class) = ErrorClass::of(&res) else {
+            return res;
+        };
+        metrics::add(class.counter(), 1);
+        let retried = &mut retried[class as usize];
+        let Some(wait) = policies[class as usize]
+            .backoff(*retried)
+            .filter(|_| retries(class))
+            .filter(|&wait| spend_budget(&mut budget_ms(), wait))
+        else {
+            return res;
+        };
+        metrics::add(Counter::Retries, 1);
+        time::sleep(wait);
+        timing::add_wait(Wait::Backoff, wait);
+        *retried += 1;
+    }
+}
@@ -0,0 +1,155 @@
+// backends the rows of a scan are read from, chosen by the source server
+// option, each one is a pipeline of building the request of a chunk of rows,
+// parsing source rows from the response and converting the source cells
+use std::fmt;
+
+use crate::api::SheetGrid;
+use crate::bindings::supabase::wrappers::{
+    http,
+    types::{Cell, FdwError, FdwResult, Options},
+};
+use crate::column::ColumnSpec;
+#[cfg(feature = "csv")]
+use crate::csv::CsvSource;
+use crate::gviz::{GvizCell, GvizRow, GvizSource};
+#[cfg(feature = "rest")]
+use crate::rest::RestSource;
+use crate::retry;
+
+pub trait Source: fmt::Debug + Send {
+    // the source reads Google spreadsheets, given by spreadsheet ids and
+    // supporting objects, rather than the single url table option
+    fn reads_spreadsheets(&self) -> bool {
+        false
+    }
+
+    // fallback options of the target columns, for sources whose cells differ
+    // from the typed cells of a sheet
+    fn column_defaults(&self) -> &'static [(&'static str, &'static str)] {
+        &[]
+    }
+
+    // check the target columns once they are resolved and prepare the
+    // requests for them
+    fn prepare(&mut self, _tgt_cols: &[ColumnSpec]) -> FdwResult {
+        Ok(())
+    }
+
+    // filter the rows of the spreadsheet fetched next by a gviz condition,
+    // set from its filter view
+    fn set_filter(&mut self, _filter: Option<String>) {}
+
+    // the cells are read by their position in the sheet grid, e.g. to fill
+    // merged cells, which needs the tab and range of each spreadsheet
+    fn reads_grid(&self) -> bool {
+        false
+    }
+
+    // rows are numbered by their sheet row, which needs the top-left cell of
+    // the range of each spreadsheet but none of its grid data
+    fn numbers_rows(&self) -> bool {
+        false
+    }
+
+    // tab and range in the sheet grid of the spreadsheet fetched next
+    fn set_grid(&mut self, _grid: Option<SheetGrid>) {}
+
+    // header labels of the sheet columns by source position, from the first
+    // chunk of the current spreadsheet, empty if the source has none
+    fn labels(&self) -> &[String] {
+        &[]
+    }
+
+    // make the request of the chunk of rows after the fetched ones
+    fn build_request(&mut self, url: &str, fetched: usize) -> http::Request;
+
+    // extract source rows from a response
+    fn parse_rows(
+        &mut self,
+        resp: &http::Response,
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError>;
+
+    // fetch the chunk of rows after the fetched ones, through the steps above
+    // unless a source needs more than one request for it
+    fn fetch(
+        &mut self,
+        url: &str,
+        fetched: usize,
+        tgt_cols: &[ColumnSpec],
+    ) -> Result<Vec<GvizRow>, FdwError> {
+        fetch_chunk(self, url, fetched, tgt_cols)
+    }
+
+    // rows per request set by the fetch_size option, None if the source
+    // fetches all rows at once
+    fn fetch_size(&self) -> Option<usize> {
+        None
+    }
+
+    // there are no more rows to fetch after a chunk of this many rows
+    fn is_last_chunk(&self, _rows: usize) -> bool {
+        true
+    }
+
+    // convert a source cell to a target cell
+    fn convert_cell(
+        &self,
+        tgt_col: &ColumnSpec,
+        src: &mut GvizCell,
+    ) -> Result<Option<Cell>, FdwError> {
+        tgt_col.convert(src)
+    }
+}
+
+impl Default for Box<dyn Source> {
+    fn default() -> Self {
+        Box::new(GvizSource::default())
+    }
+}
+
+// make the source of a scan from the source server option
+pub fn source_from_options(
+    server_opts: &Options,
+    opts: &Options,
+) -> Result<Box<dyn Source>, FdwError> {
+    Ok(match server_opts.get("source").as_deref() {
+        None | Some("gviz") => Box::new(GvizSource::new(opts)?),
+        #[cfg(feature = "rest")]
+        Some("rest") => Box::new(RestSource::new(opts)),
+        #[cfg(feature = "csv")]
+        Some("csv") => Box::new(CsvSource::new(opts)?),
+        // sources left out of the build by cargo features
+        #[cfg(not(feature = "rest"))]
+        Some("rest") => return Err(not_built("rest")),
+        #[cfg(not(feature = "csv"))]
+        Some("csv") => return Err(not_built("csv")),
+        Some(v) => {
+            return Err(format!(
+                "invalid source '{}', expect 'gviz', 'rest' or 'csv'",
+                v
+            ))
+        }
+    })
+}
+
+// error of a source left out of the build
+#[cfg(not(all(feature = "rest", feature = "csv")))]
+fn not_built(source: &str) -> FdwError {
+    format!(
+        "source '{}' is not built into this FDW, enable its cargo feature",
+        source
+    )
+}
+
+// fetch a chunk of source rows through the steps of a source
+pub fn fetch_chunk<S: Source + ?Sized>(
+    source: &mut S,
+    url: &str,
+    fetched: usize,
+    tgt_cols: &[ColumnSpec],
+) -> Result<Vec<GvizRow>, FdwError> {
+    let req = source.build_request(url, fetched);
+    let resp = retry::get(&req)?;
+    source.parse_rows(&resp, tgt_cols)
+}
@@ -0,0 +1,84 @@
+// time spent in a scan, measured if the report_timing table option is set and
+// reported at the end of the scan, the host clock only has whole seconds so
+// the scan and its HTTP requests are timed in whole seconds, which averages
+// out over many requests, while the sleeps of the rate limit and of the retry
+// backoff are known in milliseconds, parsing and converting are not timed on
+// their own as they rarely take a whole second, they are left in the rest of
+// the scan time
+use std::sync::{Mutex, MutexGuard};
+
+use crate::bindings::supabase::wrappers::{time, utils};
+
+// sleep of a scan, known in milliseconds
+#[derive(Debug, Clone, Copy)]
+pub enum Wait {
+    RateLimit,
+    Backoff,
+}
+
+#[derive(Debug, Default)]
+struct Timings {
+    // epoch seconds of the start of the scan
+    started: i64,
+    // seconds and number of the HTTP requests
+    fetch: (i64, u64),
+    // milliseconds slept by each kind of wait
+    wait_ms: [u64; 2],
+}
+
+static TIMINGS: Mutex<Option<Timings>> = Mutex::new(None);
+
+fn timings() -> MutexGuard<'static, Option<Timings>> {
+    TIMINGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// start measuring a scan, nothing is measured if not enabled
+pub fn configure(enabled: bool) {
+    *timings() = enabled.then(|| Timings {
+        started: time::epoch_secs(),
+        ..Default::default()
+    });
+}
+
+// send an HTTP request and add its time if measuring
+pub fn fetch<T>(f: impl FnOnce() -> T) -> T {
+    if timings().is_none() {
+        return f();
+    }
+    let start = time::epoch_secs();
+    let ret = f();
+    let elapsed = time::epoch_secs() - start;
+    if let Some(timings) = timings().as_mut() {
+        timings.fetch.0 += elapsed;
+        timings.fetch.1 += 1;
+    }
+    ret
+}
+
+// add a sleep of the scan
+pub fn add_wait(wait: Wait, ms: u64) {
+    if let Some(timings) = timings().as_mut() {
+        timings.wait_ms[wait as usize] += ms;
+    }
+}
+
+// report the time of the scan to the user, if measuring
+pub fn report() {
+    let Some(timings) = timings().take() else {
+        return;
+    };
+    let total = time::epoch_secs() - timings.started;
+    let (fetch_secs, requests) = timings.fetch;
+    let [rate_limit_ms, backoff_ms] = timings.wait_ms;
+    let waits = ((rate_limit_ms + backoff_ms) / 1000) as i64;
+    utils::report_info(&format!(
+        "scan timing in whole seconds: total {}s, HTTP fetch {}s ({} requests), \
+         rest {}s; waits: rate limit {:.3}s, retry backoff {:.3}s",
+        total,
+        fetch_secs,
+        requests,
+        (total - fetch_secs - waits).max(0),
+        rate_limit_ms as f64 / 1000.0,
+        backoff_ms as f64 / 1000.0
+    ));
+}